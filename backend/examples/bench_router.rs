@@ -0,0 +1,65 @@
+//! Measures how long `recalculate_router` takes after different kinds of edits. Compares the
+//! update after an edit, where only changed roads get new costs, with a freshly loaded copy of
+//! the same edits, where every road does. Either way, fast_paths prepares the whole CH again if
+//! any cost changed. Run with
+//! `cargo run --release --example bench_router -- ../web/public/areas/LAD_Edinburgh.bin`
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use backend::MapModel;
+use graph::Timer;
+
+fn main() -> Result<()> {
+    let Some(path) = std::env::args().nth(1) else {
+        bail!("Pass in a .bin file built by the CLI");
+    };
+    let bytes = std::fs::read(&path)?;
+    let load = || -> Result<MapModel> { Ok(bincode::deserialize(&bytes)?) };
+
+    let mut map = load()?;
+    map.recalculate_after_edits();
+    println!("Initial costs: {:?}", time_router(&mut map));
+
+    let edits = map.import_core_network();
+    println!("After importing {edits} routes from the core network:");
+    compare(&mut map, load()?, |fresh| {
+        fresh.import_core_network();
+        Ok(())
+    })?;
+
+    // Assume the import created at least one route
+    map.delete_route(0)?;
+    println!("After deleting one route:");
+    compare(&mut map, load()?, |fresh| {
+        fresh.import_core_network();
+        fresh.delete_route(0)
+    })?;
+
+    println!("With no edits: {:?}", time_router(&mut map));
+    Ok(())
+}
+
+/// Time the update after the latest edit to `map`, then time `fresh` with the same edits applied
+fn compare(
+    map: &mut MapModel,
+    mut fresh: MapModel,
+    replay: impl Fn(&mut MapModel) -> Result<()>,
+) -> Result<()> {
+    let incremental = time_router(map);
+    replay(&mut fresh)?;
+    let full = time_router(&mut fresh);
+    println!(
+        "  changed roads only {incremental:?}, every road {full:?} ({:.1}x faster)",
+        full.as_secs_f64() / incremental.as_secs_f64()
+    );
+    Ok(())
+}
+
+fn time_router(map: &mut MapModel) -> Duration {
+    let mut timer = Timer::new("recalculate router", None);
+    let start = Instant::now();
+    map.recalculate_router(&mut timer);
+    let duration = start.elapsed();
+    timer.done();
+    duration
+}
@@ -0,0 +1,498 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+
+use anyhow::Result;
+use enum_map::Enum;
+use geojson::FeatureCollection;
+use graph::{Direction, IntersectionID, RoadID};
+use serde::Deserialize;
+use utils::PriorityQueueItem;
+
+use crate::{
+    isochrone::can_cross,
+    routes::{glue_route, make_route_snapper_feature},
+    Dir, InfraType, LevelOfService, MapModel, Tier,
+};
+
+/// How to judge which gaps in the network matter most
+#[derive(Clone, Copy, Default, Deserialize)]
+pub enum GapWeight {
+    /// The average precalculated flow along the gap, weighted by length
+    #[default]
+    PrecalculatedFlow,
+    /// The population near the smaller of the two parts of the network being joined
+    Population,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct GapOptions {
+    pub weight: GapWeight,
+    /// Don't suggest links longer than this
+    pub max_gap_meters: f64,
+    /// How many suggestions to return
+    pub limit: usize,
+}
+
+impl Default for GapOptions {
+    fn default() -> Self {
+        Self {
+            weight: GapWeight::default(),
+            max_gap_meters: 1000.0,
+            limit: 20,
+        }
+    }
+}
+
+/// The shortest path joining two components
+struct Gap {
+    component1: usize,
+    component2: usize,
+    roads: Vec<(RoadID, Dir)>,
+    length_meters: f64,
+}
+
+/// What finding components and gaps needs to know about a road
+struct GapRoad {
+    src_i: IntersectionID,
+    dst_i: IntersectionID,
+    /// For cycling
+    access: Direction,
+    length_meters: f64,
+    /// Part of the drawn network. Routes in mixed traffic don't count, like in reachable.rs.
+    in_network: bool,
+    /// Joins a component together, because it's part of the network or has a high level of
+    /// service
+    joins: bool,
+}
+
+impl MapModel {
+    fn gap_roads(&self) -> Vec<GapRoad> {
+        let profile = self.graph.profile_names["bicycle"];
+        self.graph
+            .roads
+            .iter()
+            .enumerate()
+            .map(|(idx, road)| {
+                let in_network = self.infra_types[idx]
+                    .is_some_and(|infra_type| infra_type != InfraType::MixedTraffic);
+                GapRoad {
+                    src_i: road.src_i,
+                    dst_i: road.dst_i,
+                    access: road.access[profile.0],
+                    length_meters: road.length_meters,
+                    in_network,
+                    joins: in_network || self.los[idx] == LevelOfService::High,
+                }
+            })
+            .collect()
+    }
+
+    /// Find the shortest missing links that would join separate parts of the network, ranked by
+    /// their importance per meter. Returns GeoJSON, with each feature containing everything
+    /// needed to add it with `set_route`.
+    pub fn find_network_gaps(&self, opts: &GapOptions) -> Result<String> {
+        let roads = self.gap_roads();
+        let roads_at = |i: IntersectionID| &self.graph.intersections[i.0].roads;
+        let (components, num_components) =
+            network_components(&roads, roads_at, self.graph.intersections.len());
+
+        let mut component_population = vec![0; num_components];
+        for zone in &self.data_zones {
+            let mut touching: Vec<usize> = zone
+                .roads
+                .iter()
+                .flat_map(|r| {
+                    let road = &self.graph.roads[r.0];
+                    [components[road.src_i.0], components[road.dst_i.0]]
+                })
+                .flatten()
+                .collect();
+            touching.sort();
+            touching.dedup();
+            for c in touching {
+                component_population[c] += zone.population;
+            }
+        }
+
+        let gaps = find_gaps(
+            &roads,
+            roads_at,
+            &components,
+            num_components,
+            opts.max_gap_meters,
+        );
+        let scored = rank_gaps(gaps, |gap| match opts.weight {
+            GapWeight::PrecalculatedFlow => {
+                let mut sum = 0.0;
+                let mut length = 0.0;
+                for (r, _) in &gap.roads {
+                    let road_length = self.graph.roads[r.0].length_meters;
+                    sum += road_length * (self.precalculated_flows[r.0] as f64);
+                    length += road_length;
+                }
+                if length == 0.0 {
+                    0.0
+                } else {
+                    sum / length
+                }
+            }
+            GapWeight::Population => component_population[gap.component1]
+                .min(component_population[gap.component2])
+                as f64,
+        });
+
+        let mut features = Vec::new();
+        for (rank, (score, weight, gap)) in scored.into_iter().take(opts.limit).enumerate() {
+            let linestring = glue_route(&self.graph, &gap.roads);
+            let mut f = make_route_snapper_feature(&self.graph, &gap.roads, &linestring);
+            // The worst road along the gap decides what's needed
+            let infra_type = self.best_infra_type(
+                gap.roads
+                    .iter()
+                    .map(|(r, _)| *r)
+                    .max_by_key(|r| self.los[r.0].into_usize())
+                    .unwrap(),
+            );
+
+            f.set_property("name", format!("Suggested link {}", rank + 1));
+            f.set_property(
+                "notes",
+                format!(
+                    "joins network components {} and {}",
+                    gap.component1, gap.component2
+                ),
+            );
+            f.set_property("infra_type", serde_json::to_value(infra_type)?);
+            f.set_property("tier", serde_json::to_value(Tier::LocalAccess)?);
+            f.set_property("rank", rank + 1);
+            f.set_property("length_meters", gap.length_meters);
+            f.set_property("weight", weight);
+            f.set_property("score", score);
+            features.push(f);
+        }
+
+        let mut foreign_members = serde_json::Map::new();
+        foreign_members.insert("num_components".to_string(), num_components.into());
+        foreign_members.insert(
+            "component_population".to_string(),
+            component_population.into(),
+        );
+        Ok(serde_json::to_string(&FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: Some(foreign_members),
+        })?)
+    }
+}
+
+/// Split the drawn network into connected components, also joined by any roads with a high level
+/// of service. Returns the component of every intersection, if it has one, and the number of
+/// components. Direction doesn't matter here; it only matters for the gaps between components.
+fn network_components<'a>(
+    roads: &[GapRoad],
+    roads_at: impl Fn(IntersectionID) -> &'a Vec<RoadID>,
+    num_intersections: usize,
+) -> (Vec<Option<usize>>, usize) {
+    let mut components: Vec<Option<usize>> = vec![None; num_intersections];
+    let mut num_components = 0;
+
+    for road in roads {
+        if !road.in_network || components[road.src_i.0].is_some() {
+            continue;
+        }
+
+        // Flood from this piece of the network
+        let component = num_components;
+        num_components += 1;
+        let mut queue = vec![road.src_i, road.dst_i];
+        while let Some(i) = queue.pop() {
+            if components[i.0].is_some() {
+                continue;
+            }
+            components[i.0] = Some(component);
+            for r in roads_at(i) {
+                let road = &roads[r.0];
+                if road.joins {
+                    queue.push(road.src_i);
+                    queue.push(road.dst_i);
+                }
+            }
+        }
+    }
+
+    (components, num_components)
+}
+
+/// Find the shortest gap between each pair of nearby components. A one-way gap still joins them
+/// for trips in that direction, so the shorter direction is kept.
+fn find_gaps<'a>(
+    roads: &[GapRoad],
+    roads_at: impl Fn(IntersectionID) -> &'a Vec<RoadID> + Copy,
+    components: &[Option<usize>],
+    num_components: usize,
+    max_gap_meters: f64,
+) -> Vec<Gap> {
+    // Group the intersections of each component once, so every search starts from its own
+    let mut starts: Vec<Vec<IntersectionID>> = vec![Vec::new(); num_components];
+    for (idx, c) in components.iter().enumerate() {
+        if let Some(c) = c {
+            starts[*c].push(IntersectionID(idx));
+        }
+    }
+
+    let mut gaps: BTreeMap<(usize, usize), Gap> = BTreeMap::new();
+    for (component, starts) in starts.iter().enumerate() {
+        for gap in gaps_from(
+            roads,
+            roads_at,
+            component,
+            starts,
+            components,
+            max_gap_meters,
+        ) {
+            if gap.roads.is_empty() {
+                continue;
+            }
+            // Each pair may be found from both sides
+            let key = (
+                gap.component1.min(gap.component2),
+                gap.component1.max(gap.component2),
+            );
+            match gaps.entry(key) {
+                Entry::Vacant(entry) => {
+                    entry.insert(gap);
+                }
+                Entry::Occupied(mut entry) => {
+                    if gap.length_meters < entry.get().length_meters {
+                        entry.insert(gap);
+                    }
+                }
+            }
+        }
+    }
+    gaps.into_values().collect()
+}
+
+/// Find the shortest paths from one component, starting at all of its intersections, to every
+/// other component nearby, only crossing roads in the direction cycling is allowed
+fn gaps_from<'a>(
+    roads: &[GapRoad],
+    roads_at: impl Fn(IntersectionID) -> &'a Vec<RoadID>,
+    component: usize,
+    starts: &[IntersectionID],
+    components: &[Option<usize>],
+    max_gap_meters: f64,
+) -> Vec<Gap> {
+    let max_cost = meters(max_gap_meters);
+
+    // Costs are in centimeters
+    let mut queue: BinaryHeap<PriorityQueueItem<usize, IntersectionID>> = BinaryHeap::new();
+    for i in starts {
+        queue.push(PriorityQueueItem::new(0, *i));
+    }
+
+    let mut visited: HashSet<IntersectionID> = HashSet::new();
+    // The best cost found so far, and how it was reached
+    let mut best: HashMap<IntersectionID, usize> = HashMap::new();
+    let mut backrefs: HashMap<IntersectionID, (RoadID, Dir, IntersectionID)> = HashMap::new();
+    let mut gaps: BTreeMap<usize, Gap> = BTreeMap::new();
+
+    while let Some(item) = queue.pop() {
+        let i = item.value;
+        if visited.contains(&i) {
+            continue;
+        }
+        visited.insert(i);
+
+        if let Some(other) = components[i.0] {
+            if other != component {
+                // The first time reaching another component is the shortest link to it. Don't
+                // continue through it.
+                if let Entry::Vacant(entry) = gaps.entry(other) {
+                    let mut path = Vec::new();
+                    let mut current = i;
+                    while let Some((r, dir, prev)) = backrefs.get(&current) {
+                        path.push((*r, *dir));
+                        current = *prev;
+                    }
+                    path.reverse();
+                    entry.insert(Gap {
+                        component1: component,
+                        component2: other,
+                        roads: path,
+                        length_meters: (item.cost as f64) / 100.0,
+                    });
+                }
+                continue;
+            }
+        }
+
+        for r in roads_at(i) {
+            let road = &roads[r.0];
+            let forwards = road.src_i == i;
+            if !can_cross(road.access, forwards) {
+                continue;
+            }
+            let (next, dir) = if forwards {
+                (road.dst_i, Dir::Forwards)
+            } else {
+                (road.src_i, Dir::Backwards)
+            };
+            // Everywhere in this component is already a start
+            if components[next.0] == Some(component) {
+                continue;
+            }
+            let cost = item.cost + meters(road.length_meters);
+            if cost <= max_cost && cost < best.get(&next).cloned().unwrap_or(usize::MAX) {
+                best.insert(next, cost);
+                backrefs.insert(next, (*r, dir, i));
+                queue.push(PriorityQueueItem::new(cost, next));
+            }
+        }
+    }
+
+    gaps.into_values().collect()
+}
+
+/// Score each gap by its weight per meter, best first. Returns (score, weight, gap).
+fn rank_gaps(gaps: Vec<Gap>, weight: impl Fn(&Gap) -> f64) -> Vec<(f64, f64, Gap)> {
+    let mut scored: Vec<(f64, f64, Gap)> = gaps
+        .into_iter()
+        .map(|gap| {
+            let weight = weight(&gap);
+            let score = weight / gap.length_meters.max(1.0);
+            (score, weight, gap)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored
+}
+
+// to cm
+fn meters(x: f64) -> usize {
+    (x * 100.0).round() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Both components, each road and whether it's crossed forwards, and the length
+    type GapSummary = (usize, usize, Vec<(usize, bool)>, f64);
+
+    #[test]
+    fn test_gaps() {
+        // Intersections 0 to 7 in a line. Roads 0, 2, 4 and 6 are in the network, road 5 has a
+        // high level of service, and roads 1 and 3 are gaps.
+        let road = |src, dst, length_meters, in_network, joins| GapRoad {
+            src_i: IntersectionID(src),
+            dst_i: IntersectionID(dst),
+            access: Direction::Both,
+            length_meters,
+            in_network,
+            joins,
+        };
+        let intersections: Vec<Vec<RoadID>> = (0..8)
+            .map(|i| {
+                let mut roads = Vec::new();
+                if i > 0 {
+                    roads.push(RoadID(i - 1));
+                }
+                if i < 7 {
+                    roads.push(RoadID(i));
+                }
+                roads
+            })
+            .collect();
+        let roads_at = |i: IntersectionID| &intersections[i.0];
+
+        let mut ok = true;
+        for (description, gap_access, expected) in [
+            (
+                "two-way gaps",
+                Direction::Both,
+                vec![
+                    (0, 1, vec![(1, true)], 100.0),
+                    (1, 2, vec![(3, true)], 50.0),
+                ],
+            ),
+            (
+                "a one-way gap is found from the side it leaves",
+                Direction::Backwards,
+                vec![
+                    (0, 1, vec![(1, true)], 100.0),
+                    (2, 1, vec![(3, false)], 50.0),
+                ],
+            ),
+            (
+                "a gap that can't be cycled",
+                Direction::None,
+                vec![(0, 1, vec![(1, true)], 100.0)],
+            ),
+        ] {
+            let mut roads = vec![
+                road(0, 1, 10.0, true, true),
+                road(1, 2, 100.0, false, false),
+                road(2, 3, 10.0, true, true),
+                road(3, 4, 50.0, false, false),
+                road(4, 5, 10.0, true, true),
+                road(5, 6, 10.0, false, true),
+                road(6, 7, 10.0, true, true),
+            ];
+            roads[3].access = gap_access;
+
+            let (components, num_components) = network_components(&roads, roads_at, 8);
+            let expected_components = vec![0, 0, 1, 1, 2, 2, 2, 2];
+            if num_components != 3
+                || components
+                    != expected_components
+                        .into_iter()
+                        .map(Some)
+                        .collect::<Vec<_>>()
+            {
+                println!("For {description}, got {num_components} components: {components:?}");
+                ok = false;
+            }
+
+            let gaps = find_gaps(&roads, roads_at, &components, num_components, 1000.0);
+            let actual: Vec<GapSummary> = gaps
+                .iter()
+                .map(|gap| {
+                    (
+                        gap.component1,
+                        gap.component2,
+                        gap.roads
+                            .iter()
+                            .map(|(r, dir)| (r.0, matches!(dir, Dir::Forwards)))
+                            .collect(),
+                        gap.length_meters,
+                    )
+                })
+                .collect();
+            if actual != expected {
+                println!("For {description}, expected gaps {expected:?} but got {actual:?}");
+                ok = false;
+            }
+
+            // With the same weight, the shorter gap ranks first
+            let ranked: Vec<usize> = rank_gaps(gaps, |_| 10.0)
+                .into_iter()
+                .map(|(_, _, gap)| gap.roads[0].0 .0)
+                .collect();
+            let expected_ranks: Vec<usize> = if gap_access == Direction::None {
+                vec![1]
+            } else {
+                vec![3, 1]
+            };
+            if ranked != expected_ranks {
+                println!("For {description}, expected gaps along roads {expected_ranks:?} to rank in that order, but got {ranked:?}");
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+}
@@ -1,34 +1,118 @@
 use std::time::Duration;
 
 use geo::{Euclidean, Length};
-use graph::{Road, RoadID, Timer};
+use graph::{Direction, Road, RoadID, Router, Timer};
 
-use crate::{Highway, InfraType, LevelOfService, MapModel};
+use crate::{
+    existing::{bicycle_profile, Barrier},
+    Highway, InfraType, LevelOfService, MapModel,
+};
 
 impl MapModel {
-    /// After some kind of edit, recalculate edge costs. Overwrites the only router.
+    /// After some kind of edit, recalculate edge costs. Overwrites the only router. Only roads
+    /// changed since the last call get new costs. fast_paths can't update part of a CH, so if any
+    /// cost really changed, the whole CH is prepared again, reusing the previous node ordering.
     pub fn recalculate_router(&mut self, timer: &mut Timer) {
+        if self.dirty_roads.is_empty() {
+            return;
+        }
+
         timer.step("recalculate edge costs");
+        info!("Recalculating {} edge costs", self.dirty_roads.len());
+        let profile = self.graph.profile_names["bicycle"];
+        let mut changed = false;
+        for r in std::mem::take(&mut self.dirty_roads) {
+            let cost = edge_cost(
+                &self.graph.roads[r.0],
+                self.get_infra_type(r),
+                self.los[r.0],
+                self.barriers[r.0],
+            );
+            let old_cost = std::mem::replace(&mut self.graph.roads[r.0].cost[profile.0], cost);
+            changed |= old_cost != cost;
+        }
 
-        let mut costs = Vec::new();
-        for (idx, road) in self.graph.roads.iter().enumerate() {
-            costs.push(edge_cost(
-                road,
-                self.get_infra_type(RoadID(idx)),
-                self.los[idx],
-            ));
+        // Some edits, like renaming a route or changing to an infrastructure type with the same
+        // level of service, don't affect routing at all
+        if !changed {
+            info!("No edge costs changed, keeping the CH");
+            return;
         }
-        for (road, cost) in self.graph.roads.iter_mut().zip(costs.into_iter()) {
-            road.cost = vec![cost];
+        timer.step("recalculate CH");
+        self.graph.routers[profile.0].update_costs(&self.graph.roads, profile);
+    }
+
+    /// Which way can cyclists travel along a road? Routes with infrastructure allowing contraflow
+    /// cycling open up the reverse direction of one-way streets.
+    pub fn bicycle_access(&self, r: RoadID) -> Direction {
+        let dir = self.baseline_bicycle_access(r);
+        if dir != Direction::None
+            && self.infra_types[r.0]
+                .map(|x| x.allows_contraflow())
+                .unwrap_or(false)
+        {
+            Direction::Both
+        } else {
+            dir
+        }
+    }
+
+    /// Which way can cyclists travel along a road, ignoring any edits?
+    fn baseline_bicycle_access(&self, r: RoadID) -> Direction {
+        if self.barriers[r.0] == Some(Barrier::Impassable) {
+            return Direction::None;
+        }
+        let road = &self.graph.roads[r.0];
+        bicycle_profile(&road.osm_tags, &road.linestring).0
+    }
+
+    /// Build a router for the network without any routes, if it doesn't exist yet. Edits don't
+    /// affect it, so it's only built once, the first time something compares against it.
+    pub(crate) fn ensure_baseline_router(&mut self) {
+        if self.baseline_router.is_some() {
+            return;
         }
 
-        timer.step("recalculate CH");
         let profile = self.graph.profile_names["bicycle"];
-        self.graph.routers[profile.0].update_costs(&self.graph.roads, profile);
+        let baseline: Vec<(Direction, Duration)> = self
+            .graph
+            .roads
+            .iter()
+            .enumerate()
+            .map(|(idx, road)| {
+                let access = self.baseline_bicycle_access(RoadID(idx));
+                let cost = edge_cost(
+                    road,
+                    self.baseline_infra_type(RoadID(idx)),
+                    self.baseline_level_of_service(RoadID(idx)),
+                    self.barriers[idx],
+                );
+                (access, cost)
+            })
+            .collect();
+
+        // The router reads from the roads, so temporarily swap in the baseline access and costs
+        let mut current = Vec::new();
+        for (road, (access, cost)) in self.graph.roads.iter_mut().zip(baseline) {
+            current.push((
+                std::mem::replace(&mut road.access[profile.0], access),
+                std::mem::replace(&mut road.cost[profile.0], cost),
+            ));
+        }
+        self.baseline_router = Some(Router::new(&self.graph.roads, profile));
+        for (road, (access, cost)) in self.graph.roads.iter_mut().zip(current) {
+            road.access[profile.0] = access;
+            road.cost[profile.0] = cost;
+        }
     }
 }
 
-fn edge_cost(road: &Road, infra_type: InfraType, los: LevelOfService) -> Duration {
+fn edge_cost(
+    road: &Road,
+    infra_type: InfraType,
+    los: LevelOfService,
+    barrier: Option<Barrier>,
+) -> Duration {
     // Courtesy CycleStreets
     let _quietness = match infra_type {
         InfraType::SegregatedWide => 100,
@@ -53,6 +137,8 @@ fn edge_cost(road: &Road, infra_type: InfraType, los: LevelOfService) -> Duratio
             Highway::Residential | Highway::Service => 60,
             // TODO Check these assumptions. What does MixedTraffic even mean in this case?
             Highway::Cycleway | Highway::Footway | Highway::Pedestrian | Highway::Path => 85,
+            // The barrier penalty covers the ramp
+            Highway::Steps => 85,
         },
         // TODO Some kind of infrastructure, but unspecified. Make up a value for now.
         InfraType::Unknown => 50,
@@ -66,6 +152,14 @@ fn edge_cost(road: &Road, infra_type: InfraType, los: LevelOfService) -> Duratio
         LevelOfService::ShouldNotBeUsed => 5.0,
     };
 
+    // TODO Just making this up too. Impassable barriers block the road entirely, so they don't
+    // need a cost.
+    let barrier_penalty = if barrier == Some(Barrier::Restrictive) {
+        Duration::from_secs(30)
+    } else {
+        Duration::ZERO
+    };
+
     // TODO Ignore cyclist speed for now. Later, do include it -- slower on SharedFootway or uphill
-    Duration::from_secs_f64(penalty * road.linestring.length::<Euclidean>())
+    Duration::from_secs_f64(penalty * road.linestring.length::<Euclidean>()) + barrier_penalty
 }
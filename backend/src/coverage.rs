@@ -0,0 +1,187 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use enum_map::EnumMap;
+use graph::{IntersectionID, Road, RoadID};
+use utils::PriorityQueueItem;
+
+use crate::{places::SubArea, MapModel, Tier};
+
+/// How far every road is from one network, measured along roads
+pub(crate) struct NetworkDistances {
+    /// "All" or the tier
+    name: String,
+    /// Roads in the network are fully covered
+    roads: HashSet<RoadID>,
+    /// The distance in meters to every intersection within the limit
+    intersections: HashMap<IntersectionID, f64>,
+}
+
+impl NetworkDistances {
+    /// How much of a road, in meters, is within a distance of the network? Points along the road
+    /// are reached through whichever end is closer.
+    fn covered_length(&self, r: RoadID, road: &Road, meters: f64) -> f64 {
+        if self.roads.contains(&r) {
+            return road.length_meters;
+        }
+        let from_end = |i: IntersectionID| {
+            self.intersections
+                .get(&i)
+                .map_or(0.0, |d| (meters - d).max(0.0))
+        };
+        (from_end(road.src_i) + from_end(road.dst_i)).min(road.length_meters)
+    }
+}
+
+impl MapModel {
+    /// Measure distances from the network as a whole and each tier, up to the largest distance.
+    /// This doesn't depend on any sub-area, so it's done once for all of them.
+    pub(crate) fn network_distances(&self, distances: &[f64]) -> Vec<NetworkDistances> {
+        let mut tier_roads: EnumMap<Tier, HashSet<RoadID>> = EnumMap::default();
+        let mut all_roads = HashSet::new();
+        for route in self.routes.values() {
+            for (r, _) in &route.roads {
+                tier_roads[route.tier].insert(*r);
+                all_roads.insert(*r);
+            }
+        }
+
+        let max_distance = distances.iter().cloned().fold(0.0, f64::max);
+        let mut networks = vec![("All".to_string(), all_roads)];
+        for (tier, roads) in tier_roads {
+            networks.push((format!("{tier:?}"), roads));
+        }
+        networks
+            .into_iter()
+            .map(|(name, roads)| NetworkDistances {
+                intersections: self.distances_from_roads(&roads, max_distance),
+                name,
+                roads,
+            })
+            .collect()
+    }
+
+    /// For the network as a whole and each tier, what fraction of the population lives within
+    /// each distance of it, measured along roads? Each data zone's population is spread over its
+    /// roads by length, so a zone is only partly covered if only some of its roads are, or only
+    /// part of a long road. Reported overall, for the most deprived quintile, and per density
+    /// quintile. If an area is given, only data zones inside it count, but the network may be
+    /// anywhere.
+    pub(crate) fn population_coverage(
+        &self,
+        networks: &[NetworkDistances],
+        distances: &[f64],
+        area: Option<&SubArea>,
+    ) -> serde_json::Value {
+        let mut rows = Vec::new();
+        for network in networks {
+            for meters in distances {
+                let mut overall = Shares::default();
+                let mut deprived = Shares::default();
+                let mut density_quintiles = [Shares::default(); 5];
+
+                for zone in &self.data_zones {
+                    if !area.is_none_or(|a| a.contains_data_zone(zone)) {
+                        continue;
+                    }
+                    let total_length: f64 = zone
+                        .roads
+                        .iter()
+                        .map(|r| self.graph.roads[r.0].length_meters)
+                        .sum();
+                    let covered_length: f64 = zone
+                        .roads
+                        .iter()
+                        .map(|r| network.covered_length(*r, &self.graph.roads[r.0], *meters))
+                        .sum();
+                    let covered = if total_length == 0.0 {
+                        0.0
+                    } else {
+                        (zone.population as f64) * covered_length / total_length
+                    };
+
+                    overall.add(covered, zone.population);
+                    if zone.imd_quintile_index() == 0 {
+                        deprived.add(covered, zone.population);
+                    }
+                    density_quintiles[zone.density_quintile_index()].add(covered, zone.population);
+                }
+
+                rows.push(serde_json::json!({
+                    "network": network.name,
+                    "meters": meters,
+                    "percent_population": overall.percent(),
+                    "percent_imd_population": deprived.percent(),
+                    "percent_population_by_density_quintile": density_quintiles
+                        .iter()
+                        .map(|x| x.percent())
+                        .collect::<Vec<_>>(),
+                }));
+            }
+        }
+
+        rows.into()
+    }
+
+    /// Measure the distance in meters along any road, in either direction, from the start roads
+    /// to every intersection within the limit
+    fn distances_from_roads(
+        &self,
+        start: &HashSet<RoadID>,
+        max_distance: f64,
+    ) -> HashMap<IntersectionID, f64> {
+        let mut result = HashMap::new();
+
+        // Costs are in centimeters
+        let mut queue: BinaryHeap<PriorityQueueItem<usize, IntersectionID>> = BinaryHeap::new();
+        for r in start {
+            let road = &self.graph.roads[r.0];
+            queue.push(PriorityQueueItem::new(0, road.src_i));
+            queue.push(PriorityQueueItem::new(0, road.dst_i));
+        }
+
+        while let Some(item) = queue.pop() {
+            let i = item.value;
+            if result.contains_key(&i) {
+                continue;
+            }
+            result.insert(i, (item.cost as f64) / 100.0);
+
+            for r in &self.graph.intersections[i.0].roads {
+                let road = &self.graph.roads[r.0];
+                let next = if road.src_i == i {
+                    road.dst_i
+                } else {
+                    road.src_i
+                };
+                let cost = item.cost + ((road.length_meters * 100.0).round() as usize);
+                if !result.contains_key(&next) && (cost as f64) / 100.0 <= max_distance {
+                    queue.push(PriorityQueueItem::new(cost, next));
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// Population covered out of the total
+#[derive(Clone, Copy, Default)]
+struct Shares {
+    covered: f64,
+    total: usize,
+}
+
+impl Shares {
+    fn add(&mut self, covered: f64, total: usize) {
+        self.covered += covered;
+        self.total += total;
+    }
+
+    fn percent(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.covered / (self.total as f64)
+        }
+    }
+}
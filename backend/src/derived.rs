@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use graph::Timer;
+
+use crate::{
+    od::{CountsOD, ODOptions},
+    reachable::{Reachability, ReachabilityOptions},
+    utils::Quantiles,
+    MapModel,
+};
+
+/// Expensive things calculated from the current edits, kept until the next edit. Results are kept
+/// for every set of options used since then, so different panels don't evict each other. The
+/// options have floats, so these are small lists, not maps.
+#[derive(Default)]
+pub struct DerivedState {
+    reachability: Vec<(ReachabilityOptions, Arc<Reachability>)>,
+    flow_quintiles: Option<Arc<Quantiles>>,
+    od_counts: Vec<(ODOptions, Arc<CountsOD>)>,
+}
+
+impl MapModel {
+    /// Like `get_reachable_network_with`, but only calculated once per edit
+    pub fn reachable_network(&mut self, opts: &ReachabilityOptions) -> Arc<Reachability> {
+        if let Some((_, roads)) = self.derived.reachability.iter().find(|(x, _)| x == opts) {
+            return roads.clone();
+        }
+        let roads = Arc::new(self.get_reachable_network_with(opts));
+        self.derived
+            .reachability
+            .push((opts.clone(), roads.clone()));
+        roads
+    }
+
+    /// Quintiles of `precalculated_flows`
+    pub fn flow_quintiles(&mut self) -> Arc<Quantiles> {
+        self.derived
+            .flow_quintiles
+            .get_or_insert_with(|| Arc::new(Quantiles::new(5, &self.precalculated_flows)))
+            .clone()
+    }
+
+    /// Like `od_counts`, but only calculated once per edit. Results with per-desire line output
+    /// also answer the same options without it. Also makes sure the router reflects edits first.
+    pub fn cached_od_counts(
+        &mut self,
+        timer: &mut Timer,
+        opts: &ODOptions,
+    ) -> Result<Arc<CountsOD>> {
+        self.recalculate_router(timer);
+        if let Some((_, counts)) = self
+            .derived
+            .od_counts
+            .iter()
+            .find(|(x, _)| answers(x, opts))
+        {
+            return Ok(counts.clone());
+        }
+        timer.step("route OD trips");
+        let counts = Arc::new(self.od_counts(opts)?);
+        // A result with per-desire line output replaces the same options without it
+        if opts.per_desire_line {
+            self.derived.od_counts.retain(|(x, _)| !answers(opts, x));
+        }
+        self.derived.od_counts.push((opts.clone(), counts.clone()));
+        Ok(counts)
+    }
+}
+
+/// Can OD counts calculated with `cached` be used for `wanted`?
+fn answers(cached: &ODOptions, wanted: &ODOptions) -> bool {
+    (cached.per_desire_line || !wanted.per_desire_line)
+        && ODOptions {
+            per_desire_line: wanted.per_desire_line,
+            ..cached.clone()
+        } == *wanted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uptake::Scenario;
+
+    #[test]
+    fn test_answers() {
+        let opts = |per_desire_line, scenario| ODOptions {
+            per_desire_line,
+            scenario,
+            ..Default::default()
+        };
+        let mut ok = true;
+        for (cached, wanted, expected) in [
+            (
+                opts(false, Scenario::GoDutch),
+                opts(false, Scenario::GoDutch),
+                true,
+            ),
+            (
+                opts(true, Scenario::GoDutch),
+                opts(false, Scenario::GoDutch),
+                true,
+            ),
+            (
+                opts(false, Scenario::GoDutch),
+                opts(true, Scenario::GoDutch),
+                false,
+            ),
+            (
+                opts(true, Scenario::GoDutch),
+                opts(true, Scenario::Ebike),
+                false,
+            ),
+        ] {
+            if answers(&cached, &wanted) != expected {
+                println!(
+                    "Cached per_desire_line={}, {:?} answering per_desire_line={}, {:?} should be {expected}",
+                    cached.per_desire_line, cached.scenario, wanted.per_desire_line, wanted.scenario
+                );
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+}
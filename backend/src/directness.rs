@@ -0,0 +1,188 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use geo::{Coord, Distance, Euclidean, LineInterpolatePoint};
+use graph::{IntersectionID, PathStep, Position, RoadID, Route};
+use serde::{Deserialize, Serialize};
+use utils::PriorityQueueItem;
+
+use crate::MapModel;
+
+/// What a cycling route's length is compared against to measure directness. Everything is
+/// measured between the points where the trip snaps to the bicycle network.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum DirectnessBaseline {
+    /// The driving route
+    #[default]
+    CarRoute,
+    /// The straight line
+    StraightLine,
+    /// The shortest path along any road in either direction, ignoring one-ways, barriers, and
+    /// level of service
+    ShortestPath,
+}
+
+impl MapModel {
+    /// The point where a position is along its road
+    pub(crate) fn position_coord(&self, pos: Position) -> Coord {
+        self.graph.roads[pos.road.0]
+            .linestring
+            .line_interpolate_point(pos.fraction_along)
+            .unwrap()
+            .into()
+    }
+
+    /// The length in meters of a route between snapped positions, only counting the part of the
+    /// first and last roads that's used
+    pub(crate) fn snapped_route_length(&self, route: &Route) -> f64 {
+        self.route_road_lengths(route)
+            .into_iter()
+            .map(|(_, length)| length)
+            .sum()
+    }
+
+    /// Each road along a route, with the length of it used in meters. Only part of the first and
+    /// last roads may be used.
+    pub(crate) fn route_road_lengths(&self, route: &Route) -> Vec<(RoadID, f64)> {
+        let roads: Vec<(RoadID, bool)> = route
+            .steps
+            .iter()
+            .filter_map(|step| match step {
+                PathStep::Road { road, forwards } => Some((*road, *forwards)),
+                _ => None,
+            })
+            .collect();
+        let last = roads.len().saturating_sub(1);
+        roads
+            .iter()
+            .enumerate()
+            .map(|(idx, (r, forwards))| {
+                let (mut from, mut to) = if *forwards { (0.0, 1.0) } else { (1.0, 0.0) };
+                if idx == 0 && *r == route.start.road {
+                    from = route.start.fraction_along;
+                }
+                if idx == last && *r == route.end.road {
+                    to = route.end.fraction_along;
+                }
+                (*r, (to - from).abs() * self.graph.roads[r.0].length_meters)
+            })
+            .collect()
+    }
+
+    /// The driving route between two positions snapped to the bicycle network, if it exists
+    pub(crate) fn car_route(&self, start: Position, end: Position) -> Option<Route> {
+        let car_profile = self.graph.profile_names["car"];
+        let car_start = self
+            .graph
+            .snap_to_road(self.position_coord(start), car_profile);
+        let car_end = self
+            .graph
+            .snap_to_road(self.position_coord(end), car_profile);
+        self.graph.routers[car_profile.0]
+            .route(&self.graph, car_start, car_end)
+            .ok()
+    }
+
+    /// The length in meters to compare a route between two snapped positions against, if it
+    /// exists
+    pub(crate) fn directness_baseline_length(
+        &self,
+        baseline: DirectnessBaseline,
+        start: Position,
+        end: Position,
+    ) -> Option<f64> {
+        match baseline {
+            DirectnessBaseline::CarRoute => self
+                .car_route(start, end)
+                .map(|route| self.snapped_route_length(&route)),
+            DirectnessBaseline::StraightLine => Some(Euclidean::distance(
+                self.position_coord(start),
+                self.position_coord(end),
+            )),
+            DirectnessBaseline::ShortestPath => self.shortest_path_length(start, end),
+        }
+    }
+
+    /// A* by length over every road, ignoring direction and access. The straight line to the end
+    /// never overestimates, so the search stops once nothing left can beat the best path found.
+    fn shortest_path_length(&self, start: Position, end: Position) -> Option<f64> {
+        let start_road = &self.graph.roads[start.road.0];
+        let end_road = &self.graph.roads[end.road.0];
+        let mut best = if start.road == end.road {
+            (start.fraction_along - end.fraction_along).abs() * start_road.length_meters
+        } else {
+            f64::MAX
+        };
+
+        // From each end of the last road, how far is it to the end position?
+        let mut remaining: HashMap<IntersectionID, f64> = HashMap::new();
+        remaining.insert(end_road.src_i, end.fraction_along * end_road.length_meters);
+        let to_dst = (1.0 - end.fraction_along) * end_road.length_meters;
+        remaining
+            .entry(end_road.dst_i)
+            .and_modify(|x| *x = x.min(to_dst))
+            .or_insert(to_dst);
+
+        // Costs are in centimeters, since PriorityQueueItem needs Ord. The queue is ordered by the
+        // cost so far plus the straight line to the end, and the value tracks the cost so far.
+        let end_pt = self.position_coord(end);
+        let heuristic = |i: IntersectionID| {
+            let pt: Coord = self.graph.intersections[i.0].point.into();
+            // Round down, so it never overestimates
+            (Euclidean::distance(pt, end_pt) * 100.0) as usize
+        };
+        let mut queue: BinaryHeap<PriorityQueueItem<usize, (IntersectionID, usize)>> =
+            BinaryHeap::new();
+        for (i, meters) in [
+            (
+                start_road.src_i,
+                start.fraction_along * start_road.length_meters,
+            ),
+            (
+                start_road.dst_i,
+                (1.0 - start.fraction_along) * start_road.length_meters,
+            ),
+        ] {
+            let cost = to_cm(meters);
+            queue.push(PriorityQueueItem::new(cost + heuristic(i), (i, cost)));
+        }
+
+        let mut visited: HashSet<IntersectionID> = HashSet::new();
+        while let Some(item) = queue.pop() {
+            let (i, cost) = item.value;
+            // Nothing left in the queue can beat the best so far
+            if (item.cost as f64) / 100.0 >= best {
+                break;
+            }
+            if !visited.insert(i) {
+                continue;
+            }
+            let so_far = (cost as f64) / 100.0;
+
+            if let Some(x) = remaining.get(&i) {
+                best = best.min(so_far + x);
+            }
+
+            for r in &self.graph.intersections[i.0].roads {
+                let road = &self.graph.roads[r.0];
+                let next = if road.src_i == i {
+                    road.dst_i
+                } else {
+                    road.src_i
+                };
+                if !visited.contains(&next) {
+                    let next_cost = cost + to_cm(road.length_meters);
+                    queue.push(PriorityQueueItem::new(
+                        next_cost + heuristic(next),
+                        (next, next_cost),
+                    ));
+                }
+            }
+        }
+
+        (best < f64::MAX).then_some(best)
+    }
+}
+
+fn to_cm(meters: f64) -> usize {
+    (meters * 100.0).round() as usize
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+
+use geo::{Contains, LineInterpolatePoint};
+use graph::RoadID;
+
+use crate::{od::CountsOD, places::SubArea, reachable::Reachability, LevelOfService, MapModel};
+
+/// Totals for one group of data zones
+#[derive(Clone, Copy, Default)]
+struct Group {
+    population: usize,
+    reachable_population: usize,
+    // Summing count * length, in kilometers
+    od_km: f64,
+    od_km_high_los: f64,
+    network_meters: f64,
+}
+
+impl MapModel {
+    /// Compare data zones grouped by IMD quintile (1 is the most deprived) and by density
+    /// quintile (1 is the densest). Each group gets one row, so the output can be charted
+    /// directly. Roads are assigned to the data zone containing their middle. If an area is given,
+    /// only data zones inside it count.
+    pub(crate) fn equity_breakdown(
+        &self,
+        roads: &Reachability,
+        od: &CountsOD,
+        area: Option<&SubArea>,
+    ) -> serde_json::Value {
+        let mut road_zones = self.road_to_data_zone();
+        if let Some(area) = area {
+            road_zones.retain(|_, zone| area.contains_data_zone(&self.data_zones[*zone]));
+        }
+        let on_network: HashMap<RoadID, f64> = self
+            .routes
+            .values()
+            .flat_map(|route| route.roads.iter())
+            .map(|(r, _)| (*r, self.graph.roads[r.0].length_meters))
+            .collect();
+
+        let mut groups = Groups::default();
+        let quintiles = |zone: usize| {
+            let zone = &self.data_zones[zone];
+            (zone.imd_quintile_index(), zone.density_quintile_index())
+        };
+
+        for (idx, zone) in self.data_zones.iter().enumerate() {
+            if !area.is_none_or(|a| a.contains_data_zone(zone)) {
+                continue;
+            }
+            let reachable = if roads.covers_any(&zone.roads) {
+                zone.population
+            } else {
+                0
+            };
+            for group in groups.get(quintiles(idx)) {
+                group.population += zone.population;
+                group.reachable_population += reachable;
+            }
+        }
+
+        for (r, count) in &od.counts {
+            let Some(zone) = road_zones.get(r) else {
+                continue;
+            };
+            let km = (*count as f64) * self.graph.roads[r.0].length_meters / 1000.0;
+            let high_los = self.los[r.0] == LevelOfService::High;
+            for group in groups.get(quintiles(*zone)) {
+                group.od_km += km;
+                if high_los {
+                    group.od_km_high_los += km;
+                }
+            }
+        }
+
+        for (r, length) in &on_network {
+            let Some(zone) = road_zones.get(r) else {
+                continue;
+            };
+            for group in groups.get(quintiles(*zone)) {
+                group.network_meters += length;
+            }
+        }
+
+        groups.to_json()
+    }
+
+    /// Assign each road to the data zone containing its middle, if any
+    pub(crate) fn road_to_data_zone(&self) -> HashMap<RoadID, usize> {
+        let mut result = HashMap::new();
+        for (idx, zone) in self.data_zones.iter().enumerate() {
+            for r in &zone.roads {
+                if result.contains_key(r) {
+                    continue;
+                }
+                if let Some(pt) = self.graph.roads[r.0].linestring.line_interpolate_point(0.5) {
+                    if zone.polygon.contains(&pt) {
+                        result.insert(*r, idx);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Every data zone is in one IMD quintile group and one density quintile group
+#[derive(Default)]
+struct Groups {
+    imd: [Group; 5],
+    density: [Group; 5],
+}
+
+impl Groups {
+    /// Both groups for a zone, given its (IMD, density) quintile indices
+    fn get(&mut self, (imd, density): (usize, usize)) -> [&mut Group; 2] {
+        [&mut self.imd[imd], &mut self.density[density]]
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "imd_quintiles": rows(&self.imd, "most deprived", "least deprived"),
+            "density_quintiles": rows(&self.density, "densest", "least dense"),
+        })
+    }
+}
+
+fn rows(groups: &[Group; 5], first: &str, last: &str) -> serde_json::Value {
+    let total_population: usize = groups.iter().map(|g| g.population).sum();
+    let total_od_km: f64 = groups.iter().map(|g| g.od_km).sum();
+    let total_network_meters: f64 = groups.iter().map(|g| g.network_meters).sum();
+
+    groups
+        .iter()
+        .enumerate()
+        .map(|(idx, group)| {
+            let label = match idx {
+                0 => format!("1 ({first})"),
+                4 => format!("5 ({last})"),
+                _ => format!("{}", idx + 1),
+            };
+            serde_json::json!({
+                "quintile": idx + 1,
+                "label": label,
+                "population": group.population,
+                "percent_population": ratio(group.population as f64, total_population as f64),
+                "percent_reachable_population": ratio(
+                    group.reachable_population as f64,
+                    group.population as f64,
+                ),
+                "od_km": group.od_km,
+                "percent_od_km": ratio(group.od_km, total_od_km),
+                "percent_od_km_high_los": ratio(group.od_km_high_los, group.od_km),
+                "network_meters": group.network_meters,
+                "percent_network_meters": ratio(group.network_meters, total_network_meters),
+                "network_meters_per_1000_people": ratio(
+                    1000.0 * group.network_meters,
+                    group.population as f64,
+                ),
+            })
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+fn ratio(x: f64, total: f64) -> f64 {
+    if total == 0.0 {
+        0.0
+    } else {
+        x / total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::places::imd_quintile_index;
+
+    #[test]
+    fn test_groups() {
+        let mut ok = true;
+
+        for (imd_percentile, expected) in
+            [(0, 0), (1, 0), (20, 0), (21, 1), (80, 3), (81, 4), (100, 4)]
+        {
+            let actual = imd_quintile_index(imd_percentile);
+            if actual != expected {
+                println!("IMD percentile {imd_percentile} should be in quintile index {expected}, but got {actual}");
+                ok = false;
+            }
+        }
+
+        // (IMD percentile, density quintile index, population, reachable, OD km, OD km with a high
+        // level of service, network meters)
+        let zones = [
+            (1, 0, 100, true, 10.0, 10.0, 500.0),
+            (20, 1, 300, false, 0.0, 0.0, 0.0),
+            (21, 0, 200, true, 30.0, 0.0, 0.0),
+            (100, 4, 400, true, 0.0, 0.0, 500.0),
+        ];
+        let mut groups = Groups::default();
+        for (
+            imd_percentile,
+            density,
+            population,
+            reachable,
+            od_km,
+            od_km_high_los,
+            network_meters,
+        ) in zones
+        {
+            for group in groups.get((imd_quintile_index(imd_percentile), density)) {
+                group.population += population;
+                if reachable {
+                    group.reachable_population += population;
+                }
+                group.od_km += od_km;
+                group.od_km_high_los += od_km_high_los;
+                group.network_meters += network_meters;
+            }
+        }
+        let json = groups.to_json();
+
+        // For each quintile: percent of the population, percent of it reachable, percent of OD
+        // km, percent of that with a high level of service, percent of the network, and network
+        // meters per 1000 people
+        for (key, expected) in [
+            (
+                "imd_quintiles",
+                [
+                    [0.4, 0.25, 0.25, 1.0, 0.5, 1250.0],
+                    [0.2, 1.0, 0.75, 0.0, 0.0, 0.0],
+                    [0.0; 6],
+                    [0.0; 6],
+                    [0.4, 1.0, 0.0, 0.0, 0.5, 1250.0],
+                ],
+            ),
+            (
+                "density_quintiles",
+                [
+                    [0.3, 1.0, 1.0, 0.25, 0.5, 500000.0 / 300.0],
+                    [0.3, 0.0, 0.0, 0.0, 0.0, 0.0],
+                    [0.0; 6],
+                    [0.0; 6],
+                    [0.4, 1.0, 0.0, 0.0, 0.5, 1250.0],
+                ],
+            ),
+        ] {
+            for (idx, (row, expected)) in json[key]
+                .as_array()
+                .unwrap()
+                .iter()
+                .zip(expected)
+                .enumerate()
+            {
+                let actual = [
+                    "percent_population",
+                    "percent_reachable_population",
+                    "percent_od_km",
+                    "percent_od_km_high_los",
+                    "percent_network_meters",
+                    "network_meters_per_1000_people",
+                ]
+                .map(|field| row[field].as_f64().unwrap());
+                if actual
+                    .iter()
+                    .zip(expected)
+                    .any(|(a, b)| (a - b).abs() > 1e-9)
+                {
+                    println!("For {key} row {idx}, expected {expected:?} but got {actual:?}");
+                    ok = false;
+                }
+            }
+        }
+
+        let labels: Vec<&str> = json["imd_quintiles"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["label"].as_str().unwrap())
+            .collect();
+        if labels != vec!["1 (most deprived)", "2", "3", "4", "5 (least deprived)"] {
+            println!("Wrong IMD labels: {labels:?}");
+            ok = false;
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+}
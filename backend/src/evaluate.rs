@@ -1,10 +1,13 @@
 use anyhow::Result;
-use geo::{Coord, Euclidean, Length, LineString};
+use enum_map::EnumMap;
+use geo::{Coord, Distance, Euclidean};
 use geojson::FeatureCollection;
-use graph::PathStep;
+use graph::{PathStep, Route};
 use serde::Serialize;
 
-use crate::{InfraType, LevelOfService, MapModel};
+use crate::{
+    directness::DirectnessBaseline, existing::Barrier, InfraType, LevelOfService, MapModel,
+};
 
 pub enum Breakdown {
     None,
@@ -14,12 +17,24 @@ pub enum Breakdown {
 }
 
 impl MapModel {
-    pub fn evaluate_route(&self, pt1: Coord, pt2: Coord, breakdown: Breakdown) -> Result<String> {
+    /// If `compare_baseline` is set, also route on the network without any edits and compare.
+    /// Directness is measured against `directness_baseline`.
+    pub fn evaluate_route(
+        &mut self,
+        pt1: Coord,
+        pt2: Coord,
+        breakdown: Breakdown,
+        compare_baseline: bool,
+        directness_baseline: DirectnessBaseline,
+    ) -> Result<String> {
+        if compare_baseline {
+            self.ensure_baseline_router();
+        }
+
         let profile = self.graph.profile_names["bicycle"];
         let start = self.graph.snap_to_road(pt1, profile);
         let end = self.graph.snap_to_road(pt2, profile);
         let route = self.graph.routers[profile.0].route(&self.graph, start, end)?;
-        let full_route_linestring = route.linestring(&self.graph);
 
         let mut directions = Vec::new();
         for step in &route.steps {
@@ -31,6 +46,7 @@ impl MapModel {
                     way: road.way.to_string(),
                     infra_type: self.get_infra_type(*id),
                     los: self.los[id.0],
+                    barrier: self.barriers[id.0],
                 });
             }
         }
@@ -38,7 +54,11 @@ impl MapModel {
         let mut features = Vec::new();
         match breakdown {
             Breakdown::None => {
-                features.push(self.graph.mercator.to_wgs84_gj(&full_route_linestring));
+                features.push(
+                    self.graph
+                        .mercator
+                        .to_wgs84_gj(&route.linestring(&self.graph)),
+                );
             }
             Breakdown::LevelOfService => {
                 for (linestring, los) in route.split_linestrings(&self.graph, |r| self.los[r.0]) {
@@ -67,40 +87,159 @@ impl MapModel {
             }
         }
 
-        // TODO We should use the snapped position on the roads, for a fair comparison with
-        // full_route_linestring
-        let direct_line = LineString::new(vec![
-            self.graph.intersections[start.intersection.0].point.into(),
-            self.graph.intersections[end.intersection.0].point.into(),
-        ]);
-
-        let car_profile = self.graph.profile_names["car"];
-        let car_start = self.graph.snap_to_road(pt1, car_profile);
-        let car_end = self.graph.snap_to_road(pt2, car_profile);
-        let car_route = self.graph.routers[car_profile.0].route(&self.graph, car_start, car_end)?;
-        let car_linestring = car_route.linestring(&self.graph);
-        {
-            let mut f = self.graph.mercator.to_wgs84_gj(&car_linestring);
-            f.set_property("car_route", true);
+        // Measure everything between the snapped positions, for a fair comparison with the route
+        let direct_length =
+            Euclidean::distance(self.position_coord(start), self.position_coord(end));
+
+        // Only route driving when it's the baseline
+        let mut car_length = None;
+        if directness_baseline == DirectnessBaseline::CarRoute {
+            if let Some(car_route) = self.car_route(start, end) {
+                let mut f = self
+                    .graph
+                    .mercator
+                    .to_wgs84_gj(&car_route.linestring(&self.graph));
+                f.set_property("car_route", true);
+                features.push(f);
+                car_length = Some(self.snapped_route_length(&car_route));
+            }
+        }
+
+        let compare_length = match directness_baseline {
+            DirectnessBaseline::CarRoute => car_length.unwrap_or(0.0),
+            DirectnessBaseline::StraightLine => direct_length,
+            DirectnessBaseline::ShortestPath => self
+                .directness_baseline_length(directness_baseline, start, end)
+                .unwrap_or(0.0),
+        };
+
+        let mut foreign_members = serde_json::json!({
+            "direct_length": direct_length,
+            "car_length": car_length,
+            "route_length": self.snapped_route_length(&route),
+            "directness_baseline": directness_baseline,
+            "directness_baseline_length": compare_length,
+            "directions": directions,
+        })
+        .as_object()
+        .unwrap()
+        .clone();
+
+        if compare_baseline {
+            let baseline_route =
+                self.baseline_router
+                    .as_ref()
+                    .unwrap()
+                    .route(&self.graph, start, end)?;
+            let mut f = self
+                .graph
+                .mercator
+                .to_wgs84_gj(&baseline_route.linestring(&self.graph));
+            f.set_property("baseline_route", true);
             features.push(f);
+
+            let current = self.summarize_route(&route, compare_length, false);
+            let baseline = self.summarize_route(&baseline_route, compare_length, true);
+            foreign_members.insert("current".to_string(), current.to_json());
+            foreign_members.insert("baseline".to_string(), baseline.to_json());
+            foreign_members.insert(
+                "differences".to_string(),
+                current.difference(&baseline).to_json(),
+            );
         }
 
         Ok(serde_json::to_string(&FeatureCollection {
             features,
             bbox: None,
-            foreign_members: Some(
-                serde_json::json!({
-                    "direct_length": direct_line.length::<Euclidean>(),
-                    "car_length": car_linestring.length::<Euclidean>(),
-                    "route_length": full_route_linestring.length::<Euclidean>(),
-                    "directions": directions,
-                })
-                .as_object()
-                .unwrap()
-                .clone(),
-            ),
+            foreign_members: Some(foreign_members),
         })?)
     }
+
+    /// If `baseline` is true, describe the route as if there were no edits.
+    fn summarize_route(&self, route: &Route, compare_length: f64, baseline: bool) -> RouteSummary {
+        let length = self.snapped_route_length(route);
+
+        let mut los: EnumMap<LevelOfService, f64> = EnumMap::default();
+        let mut infra_type: EnumMap<InfraType, f64> = EnumMap::default();
+        let mut total = 0.0;
+        for step in &route.steps {
+            if let PathStep::Road { road: id, .. } = step {
+                let road_length = self.graph.roads[id.0].length_meters;
+                total += road_length;
+                if baseline {
+                    los[self.baseline_level_of_service(*id)] += road_length;
+                    infra_type[self.baseline_infra_type(*id)] += road_length;
+                } else {
+                    los[self.los[id.0]] += road_length;
+                    infra_type[self.get_infra_type(*id)] += road_length;
+                }
+            }
+        }
+        if total > 0.0 {
+            for (_, x) in &mut los {
+                *x /= total;
+            }
+            for (_, x) in &mut infra_type {
+                *x /= total;
+            }
+        }
+
+        RouteSummary {
+            length,
+            directness: if compare_length > 0.0 {
+                length / compare_length
+            } else {
+                0.0
+            },
+            los,
+            infra_type,
+        }
+    }
+}
+
+/// Shares are fractions of the route's length
+struct RouteSummary {
+    length: f64,
+    // Compared to the directness baseline
+    directness: f64,
+    los: EnumMap<LevelOfService, f64>,
+    infra_type: EnumMap<InfraType, f64>,
+}
+
+impl RouteSummary {
+    fn difference(&self, other: &RouteSummary) -> RouteSummary {
+        let mut los: EnumMap<LevelOfService, f64> = EnumMap::default();
+        for (key, x) in &mut los {
+            *x = self.los[key] - other.los[key];
+        }
+        let mut infra_type: EnumMap<InfraType, f64> = EnumMap::default();
+        for (key, x) in &mut infra_type {
+            *x = self.infra_type[key] - other.infra_type[key];
+        }
+        RouteSummary {
+            length: self.length - other.length,
+            directness: self.directness - other.directness,
+            los,
+            infra_type,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut los = serde_json::Map::new();
+        for (key, x) in &self.los {
+            los.insert(format!("{key:?}"), (*x).into());
+        }
+        let mut infra_type = serde_json::Map::new();
+        for (key, x) in &self.infra_type {
+            infra_type.insert(format!("{key:?}"), (*x).into());
+        }
+        serde_json::json!({
+            "length": self.length,
+            "directness": self.directness,
+            "los_shares": los,
+            "infra_type_shares": infra_type,
+        })
+    }
 }
 
 #[derive(Serialize)]
@@ -110,6 +249,7 @@ struct Step {
     way: String,
     infra_type: InfraType,
     los: LevelOfService,
+    barrier: Option<Barrier>,
 }
 
 fn gradient_group(gradient: f64) -> &'static str {
@@ -126,3 +266,58 @@ fn gradient_group(gradient: f64) -> &'static str {
         "> 10%"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::unedited_model;
+
+    #[test]
+    fn test_unedited_matches_baseline() {
+        let mut model = unedited_model();
+        let mercator = &model.graph.mercator;
+        let out = model
+            .evaluate_route(
+                mercator.pt_to_mercator(Coord {
+                    x: -3.204,
+                    y: 55.950,
+                }),
+                mercator.pt_to_mercator(Coord {
+                    x: -3.186,
+                    y: 55.951,
+                }),
+                Breakdown::None,
+                true,
+                DirectnessBaseline::StraightLine,
+            )
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_str(&out).unwrap();
+
+        let mut ok = true;
+        let differences = &out["differences"];
+        let mut values = vec![
+            ("length".to_string(), &differences["length"]),
+            ("directness".to_string(), &differences["directness"]),
+        ];
+        for key in ["los_shares", "infra_type_shares"] {
+            for (k, v) in differences[key].as_object().unwrap() {
+                values.push((format!("{key} {k}"), v));
+            }
+        }
+        for (key, value) in values {
+            let x = value.as_f64().unwrap();
+            if x.abs() > 1e-9 {
+                println!("Without any edits, the {key} difference should be 0, but got {x}");
+                ok = false;
+            }
+        }
+        if out["current"]["length"].as_f64().unwrap() <= 0.0 {
+            println!("The route should have some length");
+            ok = false;
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+}
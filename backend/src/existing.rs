@@ -3,7 +3,8 @@ use std::time::Duration;
 use anyhow::Result;
 use geo::{Euclidean, Length, LineString};
 use geojson::FeatureCollection;
-use graph::Direction;
+use graph::{Direction, Graph, Router};
+use serde::{Deserialize, Serialize};
 use utils::Tags;
 
 use crate::{level_of_service::get_speed_mph, InfraType, MapModel};
@@ -23,6 +24,8 @@ pub enum Highway {
     Cycleway,
     Pedestrian,
     Path,
+    /// Only with a ramp or explicit access for cyclists. Still a barrier.
+    Steps,
 }
 
 impl Highway {
@@ -53,7 +56,16 @@ impl Highway {
                     None
                 }
             }
-            // TODO Make sure we got all cases; print stuff. (steps, construction...)
+            "steps" => {
+                if tags.is("ramp:bicycle", "yes")
+                    || tags.is_any("bicycle", vec!["yes", "designated"])
+                {
+                    Some(Highway::Steps)
+                } else {
+                    None
+                }
+            }
+            // TODO Make sure we got all cases; print stuff. (construction...)
             _ => None,
         }?;
 
@@ -81,7 +93,131 @@ pub fn bicycle_profile(tags: &Tags, linestring: &LineString) -> (Direction, Dura
     // 10mph
     let speed = 4.4704;
     let cost = Duration::from_secs_f64(linestring.length::<Euclidean>() / speed);
-    (Direction::Both, cost)
+    (bicycle_direction(tags), cost)
+}
+
+/// Interpret one-way rules for cyclists, including the usual exemptions
+fn bicycle_direction(tags: &Tags) -> Direction {
+    // An explicit rule for cyclists overrides everything else
+    if tags.is("oneway:bicycle", "no") {
+        return Direction::Both;
+    }
+    if tags.is_any("oneway:bicycle", vec!["yes", "true", "1"]) {
+        return Direction::Forwards;
+    }
+    if tags.is("oneway:bicycle", "-1") {
+        return Direction::Backwards;
+    }
+
+    let dir = if tags.is_any("oneway", vec!["yes", "true", "1"]) {
+        Direction::Forwards
+    } else if tags.is_any("oneway", vec!["-1", "reverse"]) {
+        Direction::Backwards
+    } else if tags.is("oneway", "no") {
+        Direction::Both
+    } else if tags.is("junction", "roundabout")
+        || tags.is_any("highway", vec!["motorway", "motorway_link"])
+    {
+        // Implied one-ways
+        Direction::Forwards
+    } else {
+        Direction::Both
+    };
+
+    if dir != Direction::Both && has_contraflow_cycleway(tags) {
+        return Direction::Both;
+    }
+    dir
+}
+
+fn has_contraflow_cycleway(tags: &Tags) -> bool {
+    for key in [
+        "cycleway",
+        "cycleway:left",
+        "cycleway:right",
+        "cycleway:both",
+    ] {
+        if tags
+            .get(key)
+            .map(|value| value.starts_with("opposite"))
+            .unwrap_or(false)
+        {
+            return true;
+        }
+    }
+
+    is_any_key(
+        tags,
+        vec![
+            "cycleway:left:oneway",
+            "cycleway:right:oneway",
+            "cycleway:both:oneway",
+        ],
+        "no",
+    ) || is_any_key(
+        tags,
+        vec!["cycleway:left:oneway", "cycleway:right:oneway"],
+        "-1",
+    )
+}
+
+/// Something on a road, from an OSM node, that blocks or slows down cyclists
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Barrier {
+    /// Cyclists may have to slow down or dismount
+    Restrictive,
+    /// Cyclists can't get past at all
+    Impassable,
+}
+
+impl Barrier {
+    /// Classify the tags of an OSM node, or of a way for steps. Steps are treated as a barrier
+    /// both ways, because they sometimes appear as a node on crossings.
+    pub fn classify(tags: &Tags) -> Option<Self> {
+        let barrier = if tags.is("highway", "steps") {
+            if tags.is("ramp:bicycle", "yes") {
+                Barrier::Restrictive
+            } else {
+                Barrier::Impassable
+            }
+        } else {
+            match tags.get("barrier")?.as_str() {
+                "stile" | "kissing_gate" => Barrier::Impassable,
+                "gate" => {
+                    if tags.is_any("access", vec!["no", "private"]) || tags.is("locked", "yes") {
+                        Barrier::Impassable
+                    } else {
+                        Barrier::Restrictive
+                    }
+                }
+                "bollard" | "cycle_barrier" => Barrier::Restrictive,
+                _ => {
+                    return None;
+                }
+            }
+        };
+
+        // Explicit tags for cyclists override the defaults
+        if tags.is("bicycle", "no") {
+            return Some(Barrier::Impassable);
+        }
+        if tags.is_any("bicycle", vec!["yes", "designated", "dismount"]) {
+            return Some(Barrier::Restrictive);
+        }
+        Some(barrier)
+    }
+}
+
+/// Prevent cyclists from using any road with an impassable barrier. This must happen before
+/// anything snaps to the graph.
+pub fn block_impassable_roads(graph: &mut Graph, barriers: &[Option<Barrier>]) {
+    let profile = graph.profile_names["bicycle"];
+    for (road, barrier) in graph.roads.iter_mut().zip(barriers) {
+        if *barrier == Some(Barrier::Impassable) {
+            road.access[profile.0] = Direction::None;
+        }
+    }
+    graph.routers[profile.0] = Router::new(&graph.roads, profile);
 }
 
 /// This is used for the directness metric. It looks at one-ways and speed limit, but not turn
@@ -92,7 +228,11 @@ pub fn car_profile(tags: &Tags, linestring: &LineString) -> (Direction, Duration
     if let Some(hwy) = Highway::classify(tags) {
         if matches!(
             hwy,
-            Highway::Footway | Highway::Cycleway | Highway::Pedestrian | Highway::Path
+            Highway::Footway
+                | Highway::Cycleway
+                | Highway::Pedestrian
+                | Highway::Path
+                | Highway::Steps
         ) {
             return exclude;
         }
@@ -210,6 +350,8 @@ pub fn classify(tags: &Tags) -> Option<InfraType> {
 
             None
         }
+        // A ramp isn't cycling infrastructure
+        Highway::Steps => None,
     }
 }
 
@@ -257,6 +399,7 @@ mod tests {
             (vec!["highway=footway", "bicycle=designated"], true),
             (vec!["highway=footway", "bicycle=yes"], true),
             (vec!["highway=steps"], false),
+            (vec!["highway=steps", "ramp:bicycle=yes"], true),
         ] {
             let do_include =
                 bicycle_profile(&tags(&input), &LineString::new(Vec::new())).0 != Direction::None;
@@ -274,6 +417,84 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_bicycle_direction() {
+        let mut ok = true;
+        for (input, expected) in [
+            (vec!["highway=residential"], Direction::Both),
+            (
+                vec!["highway=residential", "oneway=yes"],
+                Direction::Forwards,
+            ),
+            (
+                vec!["highway=residential", "oneway=-1"],
+                Direction::Backwards,
+            ),
+            (vec!["highway=residential", "oneway=no"], Direction::Both),
+            (
+                vec!["highway=tertiary", "junction=roundabout"],
+                Direction::Forwards,
+            ),
+            (
+                vec!["highway=residential", "oneway=yes", "oneway:bicycle=no"],
+                Direction::Both,
+            ),
+            (
+                vec!["highway=residential", "oneway=yes", "cycleway=opposite"],
+                Direction::Both,
+            ),
+            (
+                vec![
+                    "highway=residential",
+                    "oneway=yes",
+                    "cycleway:left=opposite_lane",
+                ],
+                Direction::Both,
+            ),
+            (
+                vec![
+                    "highway=secondary",
+                    "oneway=yes",
+                    "cycleway:right=track",
+                    "cycleway:right:oneway=no",
+                ],
+                Direction::Both,
+            ),
+            // A lane in the same direction as traffic isn't an exemption
+            (
+                vec!["highway=secondary", "oneway=yes", "cycleway:left=lane"],
+                Direction::Forwards,
+            ),
+            (
+                vec!["highway=cycleway", "oneway:bicycle=yes"],
+                Direction::Forwards,
+            ),
+        ] {
+            let actual = bicycle_profile(&tags(&input), &LineString::new(Vec::new())).0;
+            if actual != expected {
+                println!(
+                    "For {input:?}, expected {} but got {}\n",
+                    direction_name(expected),
+                    direction_name(actual)
+                );
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+
+    fn direction_name(dir: Direction) -> &'static str {
+        match dir {
+            Direction::Forwards => "Forwards",
+            Direction::Backwards => "Backwards",
+            Direction::Both => "Both",
+            Direction::None => "None",
+        }
+    }
+
     #[test]
     fn test_classify() {
         let mut ok = true;
@@ -367,6 +588,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_barriers() {
+        let mut ok = true;
+        for (input, expected) in [
+            (vec!["barrier=bollard"], Some(Barrier::Restrictive)),
+            (vec!["barrier=cycle_barrier"], Some(Barrier::Restrictive)),
+            (vec!["barrier=gate"], Some(Barrier::Restrictive)),
+            (
+                vec!["barrier=gate", "access=private"],
+                Some(Barrier::Impassable),
+            ),
+            (vec!["barrier=stile"], Some(Barrier::Impassable)),
+            (
+                vec!["barrier=kissing_gate", "bicycle=yes"],
+                Some(Barrier::Restrictive),
+            ),
+            (
+                vec!["barrier=bollard", "bicycle=no"],
+                Some(Barrier::Impassable),
+            ),
+            (vec!["highway=steps"], Some(Barrier::Impassable)),
+            (
+                vec!["highway=steps", "ramp:bicycle=yes"],
+                Some(Barrier::Restrictive),
+            ),
+            (vec!["highway=crossing"], None),
+            (vec!["barrier=kerb"], None),
+        ] {
+            let actual = Barrier::classify(&tags(&input));
+            if actual != expected {
+                println!("For {input:?}, expected {expected:?} but got {actual:?}\n");
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+
     // TODO Upstream as a test utility
     fn tags(input: &Vec<&'static str>) -> Tags {
         let mut tags = Tags::empty();
@@ -0,0 +1,376 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::Result;
+use geo::{ConcaveHull, Coord, Euclidean, Length, LineString, MultiLineString};
+use geojson::FeatureCollection;
+use graph::{Direction, IntersectionID, RoadID};
+use utils::PriorityQueueItem;
+
+use crate::{LevelOfService, MapModel};
+
+/// Isochrones assume everyone cycles at this speed, like the default in `OutcomesConfig`
+const CYCLING_SPEED_KMH: f64 = 15.0;
+
+impl MapModel {
+    /// Flood the bicycle graph from a point until `limit` of cycling time is used up. Routes
+    /// follow the current edge costs, but the limit is the time spent cycling at a constant speed.
+    /// If `min_los` is set, only roads with at least that level of service are used. Returns a
+    /// FeatureCollection with every reached road, clipped to the part that can be reached, and a
+    /// polygon outlining them.
+    pub fn isochrone(
+        &self,
+        pt: Coord,
+        limit: Duration,
+        min_los: Option<LevelOfService>,
+    ) -> Result<String> {
+        let profile = self.graph.profile_names["bicycle"];
+        let roads: Vec<Option<FloodRoad>> = self
+            .graph
+            .roads
+            .iter()
+            .enumerate()
+            .map(|(idx, road)| {
+                let access = road.access[profile.0];
+                if access == Direction::None
+                    || min_los.is_some_and(|min| !self.los[idx].is_at_least(min))
+                {
+                    return None;
+                }
+                Some(FloodRoad {
+                    src_i: road.src_i,
+                    dst_i: road.dst_i,
+                    access,
+                    cost: road.cost[profile.0],
+                    time: Duration::from_secs_f64(road.length_meters / (CYCLING_SPEED_KMH / 3.6)),
+                })
+            })
+            .collect();
+
+        let start = self.graph.snap_to_road(pt, profile);
+        if roads[start.road.0].is_none() {
+            bail!("The start point doesn't snap to a usable road");
+        }
+
+        let reached = flood(
+            &roads,
+            |i| &self.graph.intersections[i.0].roads,
+            start.road,
+            start.fraction_along,
+            limit,
+        );
+
+        let mut features = Vec::new();
+        let mut linestrings = Vec::new();
+        for (r, reached) in reached {
+            let linestring = &self.graph.roads[r.0].linestring;
+            for (from, to) in merge_intervals(reached.intervals) {
+                let piece = if from <= 0.0 && to >= 1.0 {
+                    linestring.clone()
+                } else {
+                    slice_linestring(linestring, from, to)
+                };
+                let mut f = self.graph.mercator.to_wgs84_gj(&piece);
+                f.set_property("time_seconds", reached.time.as_secs());
+                features.push(f);
+                linestrings.push(piece);
+            }
+        }
+
+        let mut f = self
+            .graph
+            .mercator
+            .to_wgs84_gj(&MultiLineString(linestrings).concave_hull(2.0));
+        f.set_property("outline", true);
+        features.push(f);
+
+        Ok(serde_json::to_string(&FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: None,
+        })?)
+    }
+}
+
+/// What the flood needs to know about a usable road
+struct FloodRoad {
+    src_i: IntersectionID,
+    dst_i: IntersectionID,
+    access: Direction,
+    /// Decides which way to go
+    cost: Duration,
+    /// Counts against the limit
+    time: Duration,
+}
+
+/// Part of a road reached by the flood
+struct Reached {
+    /// The earliest time any part of the road is reached
+    time: Duration,
+    /// Each is a [start, end] fraction along the road, possibly overlapping
+    intervals: Vec<(f64, f64)>,
+}
+
+/// Flood from a position along a road, taking the cheapest route to each intersection that stays
+/// within the time limit. Roads that are `None` can't be used.
+fn flood<'a>(
+    roads: &[Option<FloodRoad>],
+    roads_at: impl Fn(IntersectionID) -> &'a Vec<RoadID>,
+    start: RoadID,
+    fraction_along: f64,
+    limit: Duration,
+) -> HashMap<RoadID, Reached> {
+    let mut reached: HashMap<RoadID, Reached> = HashMap::new();
+    // The value also tracks the time used so far
+    let mut queue: BinaryHeap<PriorityQueueItem<Duration, (IntersectionID, Duration)>> =
+        BinaryHeap::new();
+
+    // Start towards both ends of the snapped road, if the direction allows it
+    if let Some(road) = &roads[start.0] {
+        for forwards in [true, false] {
+            if !can_cross(road.access, forwards) {
+                continue;
+            }
+            let portion = if forwards {
+                1.0 - fraction_along
+            } else {
+                fraction_along
+            };
+            let time = road.time.mul_f64(portion);
+            if time <= limit {
+                let interval = if forwards {
+                    (fraction_along, 1.0)
+                } else {
+                    (0.0, fraction_along)
+                };
+                mark(&mut reached, start, Duration::ZERO, interval);
+                let next = if forwards { road.dst_i } else { road.src_i };
+                queue.push(PriorityQueueItem::new(
+                    road.cost.mul_f64(portion),
+                    (next, time),
+                ));
+            } else {
+                let x = limit.as_secs_f64() / road.time.as_secs_f64();
+                let interval = if forwards {
+                    (fraction_along, fraction_along + x)
+                } else {
+                    (fraction_along - x, fraction_along)
+                };
+                mark(&mut reached, start, Duration::ZERO, interval);
+            }
+        }
+    }
+
+    let mut visited: HashSet<IntersectionID> = HashSet::new();
+    while let Some(item) = queue.pop() {
+        let (i, time) = item.value;
+        if !visited.insert(i) {
+            continue;
+        }
+
+        for r in roads_at(i) {
+            let Some(road) = &roads[r.0] else {
+                continue;
+            };
+            let forwards = road.src_i == i;
+            if !can_cross(road.access, forwards) {
+                continue;
+            }
+
+            let end_time = time + road.time;
+            if end_time <= limit {
+                mark(&mut reached, *r, time, (0.0, 1.0));
+                let next = if forwards { road.dst_i } else { road.src_i };
+                if !visited.contains(&next) {
+                    queue.push(PriorityQueueItem::new(
+                        item.cost + road.cost,
+                        (next, end_time),
+                    ));
+                }
+            } else {
+                // Only part of the road can be reached
+                let x = (limit - time).as_secs_f64() / road.time.as_secs_f64();
+                let interval = if forwards { (0.0, x) } else { (1.0 - x, 1.0) };
+                mark(&mut reached, *r, time, interval);
+            }
+        }
+    }
+
+    reached
+}
+
+fn mark(reached: &mut HashMap<RoadID, Reached>, r: RoadID, time: Duration, interval: (f64, f64)) {
+    let entry = reached.entry(r).or_insert(Reached {
+        time,
+        intervals: Vec::new(),
+    });
+    entry.time = entry.time.min(time);
+    entry
+        .intervals
+        .push((interval.0.max(0.0), interval.1.min(1.0)));
+}
+
+/// Sort and combine overlapping intervals
+fn merge_intervals(mut intervals: Vec<(f64, f64)>) -> Vec<(f64, f64)> {
+    intervals.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut merged: Vec<(f64, f64)> = Vec::new();
+    for (from, to) in intervals {
+        if let Some(last) = merged.last_mut() {
+            if from <= last.1 {
+                last.1 = last.1.max(to);
+                continue;
+            }
+        }
+        merged.push((from, to));
+    }
+    merged
+}
+
+/// The part of a linestring between two fractions of its length
+fn slice_linestring(linestring: &LineString, from: f64, to: f64) -> LineString {
+    let total = linestring.length::<Euclidean>();
+    let (from, to) = (from * total, to * total);
+    let point_at = |line: geo::Line, dist: f64| {
+        let length = line.length::<Euclidean>();
+        if length == 0.0 {
+            line.start
+        } else {
+            line.start + (line.end - line.start) * (dist / length)
+        }
+    };
+
+    let mut pts = Vec::new();
+    let mut so_far = 0.0;
+    for line in linestring.lines() {
+        let length = line.length::<Euclidean>();
+        let (start, end) = (so_far, so_far + length);
+        if end >= from && start <= to {
+            if pts.is_empty() {
+                pts.push(point_at(line, from - start));
+            }
+            if end <= to {
+                pts.push(line.end);
+            } else {
+                pts.push(point_at(line, to - start));
+                break;
+            }
+        }
+        so_far = end;
+    }
+    LineString::new(pts)
+}
+
+pub(crate) fn can_cross(dir: Direction, forwards: bool) -> bool {
+    match dir {
+        Direction::Both => true,
+        Direction::Forwards => forwards,
+        Direction::Backwards => !forwards,
+        Direction::None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flood() {
+        // A line of intersections 0-1-2-3, each road taking 60s, and a shortcut from 0 to 2 that
+        // takes 60s but costs more
+        let road = |src, dst, access, cost| FloodRoad {
+            src_i: IntersectionID(src),
+            dst_i: IntersectionID(dst),
+            access,
+            cost: Duration::from_secs(cost),
+            time: Duration::from_secs(60),
+        };
+        let intersections: Vec<Vec<RoadID>> = vec![
+            vec![RoadID(0), RoadID(3)],
+            vec![RoadID(0), RoadID(1)],
+            vec![RoadID(1), RoadID(2), RoadID(3)],
+            vec![RoadID(2)],
+        ];
+
+        let mut ok = true;
+        for (description, roads, start, fraction_along, limit, expected) in [
+            (
+                "the limit clips the last road",
+                vec![
+                    Some(road(0, 1, Direction::Both, 60)),
+                    Some(road(1, 2, Direction::Both, 60)),
+                    Some(road(2, 3, Direction::Both, 60)),
+                    None,
+                ],
+                0,
+                0.0,
+                150,
+                vec![
+                    (0, vec![(0.0, 1.0)]),
+                    (1, vec![(0.0, 1.0)]),
+                    (2, vec![(0.0, 0.5)]),
+                ],
+            ),
+            (
+                "start in the middle of a road",
+                vec![Some(road(0, 1, Direction::Both, 60)), None, None, None],
+                0,
+                0.5,
+                15,
+                vec![(0, vec![(0.25, 0.75)])],
+            ),
+            (
+                "one-way roads",
+                vec![
+                    Some(road(0, 1, Direction::Both, 60)),
+                    Some(road(1, 2, Direction::Backwards, 60)),
+                    Some(road(2, 3, Direction::Both, 60)),
+                    None,
+                ],
+                0,
+                0.0,
+                300,
+                vec![(0, vec![(0.0, 1.0)])],
+            ),
+            (
+                "penalties change the route, not the time",
+                vec![
+                    Some(road(0, 1, Direction::Both, 60)),
+                    Some(road(1, 2, Direction::Both, 60)),
+                    Some(road(2, 3, Direction::Both, 60)),
+                    Some(road(0, 2, Direction::Both, 300)),
+                ],
+                0,
+                0.0,
+                90,
+                vec![
+                    (0, vec![(0.0, 1.0)]),
+                    // Reached from both ends
+                    (1, vec![(0.0, 1.0)]),
+                    (2, vec![(0.0, 0.5)]),
+                    (3, vec![(0.0, 1.0)]),
+                ],
+            ),
+        ] {
+            let reached = flood(
+                &roads,
+                |i| &intersections[i.0],
+                RoadID(start),
+                fraction_along,
+                Duration::from_secs(limit),
+            );
+            let mut actual: Vec<(usize, Vec<(f64, f64)>)> = reached
+                .into_iter()
+                .map(|(r, x)| (r.0, merge_intervals(x.intervals)))
+                .collect();
+            actual.sort_by_key(|(r, _)| *r);
+            if actual != expected {
+                println!("For {description}, expected {expected:?} but got {actual:?}");
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+}
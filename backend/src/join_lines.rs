@@ -1,30 +1,31 @@
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::hash::Hash;
 
 use geo::{Coord, Euclidean, Length, LineString};
 use graph::RoadID;
 use petgraph::graphmap::UnGraphMap;
 
-use crate::{Dir, InfraType};
+use crate::Dir;
 
-// TODO For simplicty right now, hardcodes an ID and key type. Make generic later.
+// TODO For simplicty right now, hardcodes an ID type. Make generic later.
 // TODO Upstream in geo or utils
 
 /// A linestring with a list of IDs in order and some key
-pub struct KeyedLineString {
+pub struct KeyedLineString<K> {
     pub linestring: LineString,
     pub ids: Vec<(RoadID, Dir)>,
-    pub key: InfraType,
+    pub key: K,
 }
 
 // Also contains the key. Linestrings with different keys are effectively disconnected.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct HashedPoint(isize, isize, InfraType);
+struct HashedPoint<K>(isize, isize, K);
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 struct EdgeIdx(usize);
 
-impl HashedPoint {
-    fn new(pt: Coord, key: InfraType) -> Self {
+impl<K> HashedPoint<K> {
+    fn new(pt: Coord, key: K) -> Self {
         // cm precision
         Self((pt.x * 100.0) as isize, (pt.y * 100.0) as isize, key)
     }
@@ -34,12 +35,14 @@ impl HashedPoint {
 /// a matching key.
 // TODO Seems to hang, keep it around for later, but unused right now
 #[allow(unused)]
-pub fn join_linestrings(mut lines: Vec<KeyedLineString>) -> Vec<KeyedLineString> {
+pub fn join_linestrings<K: Copy + Ord + Hash>(
+    mut lines: Vec<KeyedLineString<K>>,
+) -> Vec<KeyedLineString<K>> {
     loop {
         log::info!("join_linestrings: {} lines left", lines.len());
         // Build a graph from the lines
-        let mut intersections: BTreeSet<HashedPoint> = BTreeSet::new();
-        let mut graph: UnGraphMap<HashedPoint, EdgeIdx> = UnGraphMap::new();
+        let mut intersections: BTreeSet<HashedPoint<K>> = BTreeSet::new();
+        let mut graph: UnGraphMap<HashedPoint<K>, EdgeIdx> = UnGraphMap::new();
 
         for (idx, line) in lines.iter().enumerate() {
             let i1 = HashedPoint::new(*line.linestring.0.first().unwrap(), line.key);
@@ -58,52 +61,90 @@ pub fn join_linestrings(mut lines: Vec<KeyedLineString>) -> Vec<KeyedLineString>
 }
 
 /// Find all linestrings that meet at one end and join them. Only joins lines with a matching key.
-pub fn collapse_degree_2(mut lines: Vec<KeyedLineString>) -> Vec<KeyedLineString> {
+pub fn collapse_degree_2<K: Copy + Ord + Hash>(
+    lines: Vec<KeyedLineString<K>>,
+) -> Vec<KeyedLineString<K>> {
     info!("Collapsing degree 2 nodes in {} lines", lines.len());
-    // TODO I think this is doable in one pass
-    loop {
-        let mut intersections: HashMap<HashedPoint, EdgeIdx> = HashMap::new();
+    // Joined lines are taken out and the result is added to the end
+    let mut lines: Vec<Option<KeyedLineString<K>>> = lines.into_iter().map(Some).collect();
+    // Every line ending at each point, including ones already joined
+    let mut endpoints: HashMap<HashedPoint<K>, Vec<usize>> = HashMap::new();
+    // Points where lines might be joinable
+    let mut queue: VecDeque<HashedPoint<K>> = VecDeque::new();
 
-        let mut path = None;
-        'FIND: for (idx1, line) in lines.iter().enumerate() {
-            let i1 = HashedPoint::new(*line.linestring.0.first().unwrap(), line.key);
-            let i2 = HashedPoint::new(*line.linestring.0.last().unwrap(), line.key);
-            if i1 == i2 {
-                continue;
-            }
+    for (idx, line) in lines.iter().enumerate() {
+        add_endpoints(idx, line.as_ref().unwrap(), &mut endpoints, &mut queue);
+    }
 
-            let idx1 = EdgeIdx(idx1);
-            for i in [i1, i2] {
-                match intersections.get(&i) {
-                    Some(idx2) => {
-                        // Don't create a loop though!
-                        // TODO Doesn't seem to always work
-                        if number_shared_endpoints(line, &lines[idx2.0]) == 1 {
-                            path = Some(vec![idx1, *idx2]);
-                            break 'FIND;
-                        }
-                    }
-                    None => {
-                        intersections.insert(i, idx1);
-                    }
-                }
-            }
-        }
+    while let Some(pt) = queue.pop_front() {
+        let Some(pair) = endpoints.get_mut(&pt).and_then(|indices| {
+            indices.retain(|idx| lines[*idx].is_some());
+            find_pair(&lines, indices)
+        }) else {
+            continue;
+        };
 
-        if let Some(path) = path {
-            lines = join_path(lines, path);
-        } else {
-            break;
+        let line1 = lines[pair.0].take().unwrap();
+        let line2 = lines[pair.1].take().unwrap();
+        let joined = join_two(line1, line2);
+        add_endpoints(lines.len(), &joined, &mut endpoints, &mut queue);
+        lines.push(Some(joined));
+        // More lines might meet here
+        queue.push_back(pt);
+    }
+
+    lines.into_iter().flatten().collect()
+}
+
+fn add_endpoints<K: Copy + Ord + Hash>(
+    idx: usize,
+    line: &KeyedLineString<K>,
+    endpoints: &mut HashMap<HashedPoint<K>, Vec<usize>>,
+    queue: &mut VecDeque<HashedPoint<K>>,
+) {
+    let (i1, i2) = line_endpoints(line);
+    // Loops can't be joined to anything
+    if i1 == i2 {
+        return;
+    }
+    for i in [i1, i2] {
+        endpoints.entry(i).or_default().push(idx);
+        queue.push_back(i);
+    }
+}
+
+/// Find two lines that can be joined at a point they both end at
+fn find_pair<K: Copy + Ord + Hash>(
+    lines: &[Option<KeyedLineString<K>>],
+    indices: &[usize],
+) -> Option<(usize, usize)> {
+    for (pos, idx1) in indices.iter().enumerate() {
+        for idx2 in &indices[pos + 1..] {
+            // Don't create a loop though!
+            if number_shared_endpoints(
+                lines[*idx1].as_ref().unwrap(),
+                lines[*idx2].as_ref().unwrap(),
+            ) == 1
+            {
+                return Some((*idx1, *idx2));
+            }
         }
     }
-    lines
+    None
+}
+
+fn line_endpoints<K: Copy>(line: &KeyedLineString<K>) -> (HashedPoint<K>, HashedPoint<K>) {
+    (
+        HashedPoint::new(*line.linestring.0.first().unwrap(), line.key),
+        HashedPoint::new(*line.linestring.0.last().unwrap(), line.key),
+    )
 }
 
 // Of length > 1
-fn find_longest_path(
-    graph: &UnGraphMap<HashedPoint, EdgeIdx>,
-    edges: &Vec<KeyedLineString>,
-    intersections: &BTreeSet<HashedPoint>,
+fn find_longest_path<K: Copy + Ord + Hash>(
+    graph: &UnGraphMap<HashedPoint<K>, EdgeIdx>,
+    edges: &Vec<KeyedLineString<K>>,
+    intersections: &BTreeSet<HashedPoint<K>>,
 ) -> Option<Vec<EdgeIdx>> {
     let mut best_path = Vec::new();
     let mut best_length = 0.0;
@@ -142,65 +183,24 @@ fn find_longest_path(
 }
 
 // Combines everything in the path, returning a smaller list of lines
-fn join_path(lines: Vec<KeyedLineString>, path: Vec<EdgeIdx>) -> Vec<KeyedLineString> {
-    // Build up the joined line
-    let mut points = Vec::new();
-    let mut ids = Vec::new();
-    // Trust the caller to only pass in the correct key
-    let key = lines[path[0].0].key;
-
+fn join_path<K: Copy + Ord + Hash>(
+    lines: Vec<KeyedLineString<K>>,
+    path: Vec<EdgeIdx>,
+) -> Vec<KeyedLineString<K>> {
+    let mut joined: Option<KeyedLineString<K>> = None;
     for idx in &path {
-        let mut next_ids = lines[idx.0].ids.clone();
-        let mut next_points = lines[idx.0].linestring.clone().into_inner();
-
-        if points.is_empty() {
-            points = next_points;
-            ids = next_ids;
-            continue;
-        }
-        let pt1 = HashedPoint::new(*points.first().unwrap(), key);
-        let pt2 = HashedPoint::new(*points.last().unwrap(), key);
-        let pt3 = HashedPoint::new(*next_points.first().unwrap(), key);
-        let pt4 = HashedPoint::new(*next_points.last().unwrap(), key);
-
-        if pt1 == pt3 {
-            points.reverse();
-            points.pop();
-            points.extend(next_points);
-
-            ids.reverse();
-            flip_direction(&mut ids);
-            ids.extend(next_ids);
-        } else if pt1 == pt4 {
-            next_points.pop();
-            next_points.extend(points);
-            points = next_points;
-
-            next_ids.extend(ids);
-            ids = next_ids;
-        } else if pt2 == pt3 {
-            points.pop();
-            points.extend(next_points);
-
-            ids.extend(next_ids);
-        } else if pt2 == pt4 {
-            next_points.reverse();
-            points.pop();
-            points.extend(next_points);
-
-            next_ids.reverse();
-            flip_direction(&mut next_ids);
-            ids.extend(next_ids);
-        } else {
-            unreachable!()
-        }
+        let next = KeyedLineString {
+            linestring: lines[idx.0].linestring.clone(),
+            ids: lines[idx.0].ids.clone(),
+            key: lines[idx.0].key,
+        };
+        joined = Some(match joined {
+            Some(line) => join_two(line, next),
+            None => next,
+        });
     }
 
-    let mut result = vec![KeyedLineString {
-        linestring: LineString::new(points),
-        ids,
-        key,
-    }];
+    let mut result = vec![joined.unwrap()];
 
     // Leftovers
     for (i, line) in lines.into_iter().enumerate() {
@@ -211,7 +211,66 @@ fn join_path(lines: Vec<KeyedLineString>, path: Vec<EdgeIdx>) -> Vec<KeyedLineSt
     result
 }
 
-fn number_shared_endpoints(line1: &KeyedLineString, line2: &KeyedLineString) -> usize {
+// Joins two lines sharing one endpoint
+fn join_two<K: Copy + Ord + Hash>(
+    line1: KeyedLineString<K>,
+    line2: KeyedLineString<K>,
+) -> KeyedLineString<K> {
+    // Trust the caller to only pass in the correct key
+    let key = line1.key;
+    let mut points = line1.linestring.into_inner();
+    let mut ids = line1.ids;
+    let mut next_points = line2.linestring.into_inner();
+    let mut next_ids = line2.ids;
+
+    let pt1 = HashedPoint::new(*points.first().unwrap(), key);
+    let pt2 = HashedPoint::new(*points.last().unwrap(), key);
+    let pt3 = HashedPoint::new(*next_points.first().unwrap(), key);
+    let pt4 = HashedPoint::new(*next_points.last().unwrap(), key);
+
+    if pt1 == pt3 {
+        points.reverse();
+        points.pop();
+        points.extend(next_points);
+
+        ids.reverse();
+        flip_direction(&mut ids);
+        ids.extend(next_ids);
+    } else if pt1 == pt4 {
+        next_points.pop();
+        next_points.extend(points);
+        points = next_points;
+
+        next_ids.extend(ids);
+        ids = next_ids;
+    } else if pt2 == pt3 {
+        points.pop();
+        points.extend(next_points);
+
+        ids.extend(next_ids);
+    } else if pt2 == pt4 {
+        next_points.reverse();
+        points.pop();
+        points.extend(next_points);
+
+        next_ids.reverse();
+        flip_direction(&mut next_ids);
+        ids.extend(next_ids);
+    } else {
+        unreachable!()
+    }
+
+    KeyedLineString {
+        linestring: LineString::new(points),
+        ids,
+        key,
+    }
+}
+
+fn number_shared_endpoints<K: Copy + Ord + Hash>(
+    line1: &KeyedLineString<K>,
+    line2: &KeyedLineString<K>,
+) -> usize {
     let mut set = BTreeSet::new();
     set.insert(HashedPoint::new(
         *line1.linestring.0.first().unwrap(),
@@ -240,3 +299,66 @@ fn flip_direction(ids: &mut Vec<(RoadID, Dir)>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collapse_degree_2() {
+        // Each line is (ID, key, points)
+        let line = |id, key, pts: Vec<(f64, f64)>| KeyedLineString {
+            linestring: LineString::from(pts),
+            ids: vec![(RoadID(id), Dir::Forwards)],
+            key,
+        };
+
+        let mut ok = true;
+        for (description, input, expected) in [
+            (
+                "a chain, with the middle line pointing the other way",
+                vec![
+                    line(0, 0, vec![(0.0, 0.0), (1.0, 0.0)]),
+                    line(1, 0, vec![(2.0, 0.0), (1.0, 0.0)]),
+                    line(2, 0, vec![(2.0, 0.0), (3.0, 0.0)]),
+                ],
+                vec![vec![(0, true), (1, false), (2, true)]],
+            ),
+            (
+                "different keys",
+                vec![
+                    line(0, 0, vec![(0.0, 0.0), (1.0, 0.0)]),
+                    line(1, 1, vec![(1.0, 0.0), (2.0, 0.0)]),
+                ],
+                vec![vec![(0, true)], vec![(1, true)]],
+            ),
+            (
+                "two lines forming a loop",
+                vec![
+                    line(0, 0, vec![(0.0, 0.0), (1.0, 0.0)]),
+                    line(1, 0, vec![(1.0, 0.0), (1.0, 1.0), (0.0, 0.0)]),
+                ],
+                vec![vec![(0, true)], vec![(1, true)]],
+            ),
+        ] {
+            let mut actual: Vec<Vec<(usize, bool)>> = collapse_degree_2(input)
+                .into_iter()
+                .map(|line| {
+                    line.ids
+                        .into_iter()
+                        .map(|(r, dir)| (r.0, matches!(dir, Dir::Forwards)))
+                        .collect()
+                })
+                .collect();
+            actual.sort();
+            if actual != expected {
+                println!("For {description}, expected {expected:?} but got {actual:?}");
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+}
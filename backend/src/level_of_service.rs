@@ -2,12 +2,13 @@ use anyhow::Result;
 use enum_map::Enum;
 use geojson::FeatureCollection;
 use graph::RoadID;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use utils::Tags;
 
 use crate::{Highway, InfraType, MapModel};
 
-#[derive(Clone, Copy, Debug, PartialEq, Enum, Serialize)]
+// Ordered from best to worst
+#[derive(Clone, Copy, Debug, PartialEq, Enum, Serialize, Deserialize)]
 pub enum LevelOfService {
     High,
     Medium,
@@ -15,6 +16,13 @@ pub enum LevelOfService {
     ShouldNotBeUsed,
 }
 
+impl LevelOfService {
+    /// Is this as good as or better than `other`?
+    pub fn is_at_least(self, other: LevelOfService) -> bool {
+        (self as usize) <= (other as usize)
+    }
+}
+
 impl MapModel {
     pub fn render_level_of_service(&self) -> Result<String> {
         let mut features = Vec::new();
@@ -28,6 +36,7 @@ impl MapModel {
             f.set_property("speed", self.speeds[idx]);
             // TODO Abusing this here; need to consolidate the output layers
             f.set_property("gradient", self.gradients[idx]);
+            f.set_property("barrier", serde_json::to_value(self.barriers[idx])?);
             features.push(f);
         }
 
@@ -41,7 +50,16 @@ impl MapModel {
     // TODO Implement directly from
     // https://www.transport.gov.scot/media/50323/cycling-by-design-update-2019-final-document-15-september-2021-1.pdf?
     pub fn calculate_level_of_service(&self, r: RoadID) -> LevelOfService {
-        let infra_type = self.get_infra_type(r);
+        self.level_of_service_with(r, self.get_infra_type(r))
+    }
+
+    /// The level of service on a road without any edits
+    pub fn baseline_level_of_service(&self, r: RoadID) -> LevelOfService {
+        self.level_of_service_with(r, self.baseline_infra_type(r))
+    }
+
+    /// What would the level of service be if this road had the given infrastructure?
+    pub fn level_of_service_with(&self, r: RoadID, infra_type: InfraType) -> LevelOfService {
         let speed = self.speeds[r.0];
         let traffic = self.traffic_volumes[r.0];
         // TODO Total placeholder
@@ -108,6 +126,10 @@ pub fn get_speed_mph(tags: &Tags) -> usize {
         Highway::Secondary | Highway::Tertiary => 30,
         Highway::Residential | Highway::Service | Highway::Unclassified => 20,
         // TODO What should these do?
-        Highway::Footway | Highway::Cycleway | Highway::Pedestrian | Highway::Path => 10,
+        Highway::Footway
+        | Highway::Cycleway
+        | Highway::Pedestrian
+        | Highway::Path
+        | Highway::Steps => 10,
     }
 }
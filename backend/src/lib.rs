@@ -3,29 +3,42 @@ extern crate anyhow;
 #[macro_use]
 extern crate log;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use enum_map::Enum;
 use geo::MultiPolygon;
 use geojson::Feature;
-use graph::{Graph, RoadID};
+use graph::{Graph, RoadID, Router};
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 
-use crate::{existing::Highway, level_of_service::LevelOfService};
+use crate::{
+    existing::{Barrier, Highway},
+    level_of_service::LevelOfService,
+};
 
+mod connectivity;
 mod costs;
+mod coverage;
+mod derived;
+mod directness;
+mod equity;
 mod evaluate;
 pub mod existing;
+mod isochrone;
 mod join_lines;
 mod level_of_service;
 mod mesh_density;
 pub mod od;
+mod outcomes;
 pub mod places;
 mod precalculated_flow;
 mod reachable;
+pub mod rnet;
 mod route_snapper;
 mod routes;
+pub mod severance;
+mod snapshots;
 mod stats;
 mod uptake;
 mod utils;
@@ -40,17 +53,21 @@ pub struct MapModel {
     routes: HashMap<usize, Route>,
     #[serde(skip_serializing, skip_deserializing, default)]
     id_counter: usize,
+    // Kept in the savefile, like routes
+    #[serde(skip_serializing, skip_deserializing, default)]
+    snapshots: BTreeMap<String, snapshots::Snapshot>,
 
     boundary_wgs84: MultiPolygon,
 
     od_zones: HashMap<String, od::Zone>,
     // TODO Use more compact encoding for zone names
-    desire_lines: Vec<(String, String, usize)>,
+    desire_lines: BTreeMap<od::TripPurpose, Vec<od::DesireLine>>,
 
     schools: Vec<places::School>,
     gp_hospitals: Vec<places::GPHospital>,
     town_centres: Vec<places::TownCentre>,
     data_zones: Vec<places::DataZone>,
+    sub_areas: Vec<places::SubArea>,
 
     // Per RoadID
     traffic_volumes: Vec<usize>,
@@ -61,11 +78,26 @@ pub struct MapModel {
     speeds: Vec<usize>,
     // A percent. Positive if uphill in the forwards direction, negative if downhill
     gradients: Vec<f64>,
+    // The worst barrier along the road, if any
+    barriers: Vec<Option<Barrier>>,
     // Derived things maintained by recalculate_after_edits
     #[serde(skip_serializing, skip_deserializing, default)]
     infra_types: Vec<Option<InfraType>>,
+    // From OSM, for roads without a route. Only depends on the graph.
+    #[serde(skip_serializing, skip_deserializing, default)]
+    existing_infra_types: Vec<InfraType>,
     #[serde(skip_serializing, skip_deserializing, default)]
     los: Vec<LevelOfService>,
+    // Roads whose edge cost may have changed since the last recalculate_router
+    #[serde(skip_serializing, skip_deserializing, default)]
+    dirty_roads: HashSet<RoadID>,
+    // Cleared by recalculate_after_edits and lazily filled in
+    #[serde(skip_serializing, skip_deserializing, default)]
+    derived: derived::DerivedState,
+    // Routes on the network without any edits. Built the first time something compares against
+    // the baseline and never invalidated.
+    #[serde(skip_serializing, skip_deserializing, default)]
+    baseline_router: Option<Router>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -99,6 +131,19 @@ pub enum InfraType {
     Unknown,
 }
 
+impl InfraType {
+    /// Can cyclists use this in both directions, even along a one-way street?
+    pub fn allows_contraflow(self) -> bool {
+        matches!(
+            self,
+            InfraType::SegregatedWide
+                | InfraType::OffRoad
+                | InfraType::SegregatedNarrow
+                | InfraType::SharedFootway
+        )
+    }
+}
+
 #[derive(Clone, Copy, Debug, Enum, Serialize, Deserialize)]
 pub enum Tier {
     Primary,
@@ -113,15 +158,17 @@ impl MapModel {
         graph: Graph,
         boundary_wgs84: MultiPolygon,
         od_zones: HashMap<String, od::Zone>,
-        desire_lines: Vec<(String, String, usize)>,
+        desire_lines: BTreeMap<od::TripPurpose, Vec<od::DesireLine>>,
         schools: Vec<places::School>,
         gp_hospitals: Vec<places::GPHospital>,
         town_centres: Vec<places::TownCentre>,
         data_zones: Vec<places::DataZone>,
+        sub_areas: Vec<places::SubArea>,
         traffic_volumes: Vec<usize>,
         core_network: Vec<Option<Tier>>,
         precalculated_flows: Vec<usize>,
         gradients: Vec<f64>,
+        barriers: Vec<Option<Barrier>>,
     ) -> Self {
         let speeds = graph
             .roads
@@ -132,10 +179,13 @@ impl MapModel {
         let los = std::iter::repeat(LevelOfService::ShouldNotBeUsed)
             .take(graph.roads.len())
             .collect();
+        // The graph was built with distance-based costs, so everything needs updating once
+        let dirty_roads = (0..graph.roads.len()).map(RoadID).collect();
         Self {
             graph,
             routes: HashMap::new(),
             id_counter: 0,
+            snapshots: BTreeMap::new(),
             boundary_wgs84,
             od_zones,
             desire_lines,
@@ -143,17 +193,37 @@ impl MapModel {
             gp_hospitals,
             town_centres,
             data_zones,
+            sub_areas,
             traffic_volumes,
             core_network,
             precalculated_flows,
             speeds,
             gradients,
+            barriers,
             infra_types,
+            existing_infra_types: Vec::new(),
             los,
+            dirty_roads,
+            derived: derived::DerivedState::default(),
+            baseline_router: None,
         }
     }
 
     pub fn recalculate_after_edits(&mut self) {
+        // Right after deserializing, these are empty, and every road is considered changed
+        let old_infra_types = std::mem::take(&mut self.infra_types);
+        let old_los = std::mem::take(&mut self.los);
+        self.derived = derived::DerivedState::default();
+
+        if self.existing_infra_types.len() != self.graph.roads.len() {
+            self.existing_infra_types = self
+                .graph
+                .roads
+                .iter()
+                .map(|road| existing::classify(&road.osm_tags).unwrap_or(InfraType::MixedTraffic))
+                .collect();
+        }
+
         self.infra_types = std::iter::repeat(None)
             .take(self.graph.roads.len())
             .collect();
@@ -167,9 +237,122 @@ impl MapModel {
         self.los = (0..self.graph.roads.len())
             .map(|idx| self.calculate_level_of_service(RoadID(idx)))
             .collect();
+
+        // The edge cost and direction only depend on these two things, so track which roads
+        // changed
+        let profile = self.graph.profile_names["bicycle"];
+        for idx in 0..self.graph.roads.len() {
+            if old_infra_types.get(idx) != Some(&self.infra_types[idx])
+                || old_los.get(idx) != Some(&self.los[idx])
+            {
+                self.dirty_roads.insert(RoadID(idx));
+                self.graph.roads[idx].access[profile.0] = self.bicycle_access(RoadID(idx));
+            }
+        }
     }
 
+    /// The infrastructure from the route over a road, or what's already in OSM
     pub fn get_infra_type(&self, r: RoadID) -> InfraType {
-        self.infra_types[r.0].unwrap_or(InfraType::MixedTraffic)
+        self.infra_types[r.0].unwrap_or(self.existing_infra_types[r.0])
+    }
+
+    /// The infrastructure on a road without any edits, from OSM. Roads without a route have the
+    /// same on the current network, so an unedited network matches the baseline.
+    pub fn baseline_infra_type(&self, r: RoadID) -> InfraType {
+        self.existing_infra_types[r.0]
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use geo::{coord, Rect};
+    use graph::Timer;
+
+    use super::*;
+
+    /// A few roads across two OD zones, with a desire line between them and no edits
+    pub(crate) fn unedited_model() -> MapModel {
+        // A residential street and a primary road joined by a cycleway
+        let osm = r#"<osm version="0.6">
+            <node id="1" lat="55.950" lon="-3.205" />
+            <node id="2" lat="55.950" lon="-3.195" />
+            <node id="3" lat="55.950" lon="-3.185" />
+            <node id="4" lat="55.952" lon="-3.200" />
+            <way id="10">
+                <nd ref="1" /><nd ref="2" /><nd ref="3" />
+                <tag k="highway" v="residential" />
+                <tag k="maxspeed" v="20 mph" />
+            </way>
+            <way id="11">
+                <nd ref="2" /><nd ref="4" />
+                <tag k="highway" v="cycleway" />
+            </way>
+            <way id="12">
+                <nd ref="1" /><nd ref="4" /><nd ref="3" />
+                <tag k="highway" v="primary" />
+                <tag k="maxspeed" v="40 mph" />
+            </way>
+        </osm>"#;
+        let graph = Graph::new(
+            osm.as_bytes(),
+            &mut ::utils::osm2graph::NullReader,
+            vec![
+                ("bicycle".to_string(), Box::new(existing::bicycle_profile)),
+                ("car".to_string(), Box::new(existing::car_profile)),
+            ],
+            &mut Timer::new("build test graph", None),
+        )
+        .unwrap();
+
+        let rect =
+            |x1, x2| Rect::new(coord! { x: x1, y: 55.94 }, coord! { x: x2, y: 55.96 }).to_polygon();
+        let boundary_wgs84 = MultiPolygon(vec![rect(-3.21, -3.18)]);
+        let zone = |name, x1, x2| {
+            let mut f = Feature::from(geojson::Geometry::new(geojson::Value::from(&rect(x1, x2))));
+            f.set_property("InterZone", name);
+            f
+        };
+        let zones = geojson::FeatureCollection {
+            features: vec![zone("west", -3.21, -3.195), zone("east", -3.195, -3.18)],
+            bbox: None,
+            foreign_members: None,
+        };
+        let od_zones = od::Zone::parse_zones(
+            serde_json::to_string(&zones).unwrap(),
+            &boundary_wgs84,
+            &graph.mercator,
+        )
+        .unwrap();
+        let mut desire_lines = BTreeMap::new();
+        desire_lines.insert(
+            od::TripPurpose::Commute,
+            vec![od::DesireLine {
+                zone1: "west".to_string(),
+                zone2: "east".to_string(),
+                all: 100,
+                bicycle: 5,
+            }],
+        );
+
+        let n = graph.roads.len();
+        let mut model = MapModel::create(
+            graph,
+            boundary_wgs84,
+            od_zones,
+            desire_lines,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            vec![500; n],
+            vec![None; n],
+            vec![0; n],
+            vec![0.0; n],
+            vec![None; n],
+        );
+        model.recalculate_after_edits();
+        model.recalculate_router(&mut Timer::new("test router", None));
+        model
     }
 }
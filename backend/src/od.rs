@@ -1,15 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use anyhow::Result;
-use enum_map::EnumMap;
-use geo::{BoundingRect, Contains, Coord, Distance, Euclidean, Intersects, MultiPolygon};
+use enum_map::{Enum, EnumMap};
+use geo::{
+    Area, BoundingRect, Centroid, Contains, Coord, Intersects, LineInterpolatePoint, LineString,
+    MultiPolygon,
+};
 use geojson::{FeatureCollection, Value};
-use graph::{PathStep, RoadID};
+use graph::{Direction, PathStep, RoadID, Route, Timer};
 use nanorand::{Rng, WyRand};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use utils::Mercator;
 
-use crate::{uptake, InfraType, MapModel};
+use crate::{
+    directness::DirectnessBaseline,
+    uptake::{Scenario, UptakeModel},
+    InfraType, LevelOfService, MapModel,
+};
 
 pub struct CountsOD {
     pub counts: HashMap<RoadID, usize>,
@@ -18,145 +27,453 @@ pub struct CountsOD {
     pub average_weighted_directness: f64,
 
     pub worst_directness_routes: Vec<(Coord, Coord)>,
+
+    /// Only filled out if `ODOptions::per_desire_line` is set
+    pub desire_lines: Vec<DesireLineResult>,
+
+    /// The same results for each trip purpose separately. The fields above combine all purposes.
+    /// This is empty for the per-purpose results.
+    pub per_purpose: BTreeMap<TripPurpose, CountsOD>,
 }
 
-impl MapModel {
-    pub fn od_counts(&self) -> Result<CountsOD> {
-        let keep_directness_routes = 10;
+/// Trips between two zones for one purpose
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DesireLine {
+    pub zone1: String,
+    pub zone2: String,
+    /// Trips by any mode
+    pub all: usize,
+    /// Trips cycled today
+    pub bicycle: usize,
+}
+
+impl DesireLine {
+    /// The fraction of trips cycled today
+    pub(crate) fn current_cycling(&self) -> f64 {
+        if self.all == 0 {
+            0.0
+        } else {
+            (self.bicycle as f64 / self.all as f64).min(1.0)
+        }
+    }
+}
+
+/// Why people make trips. Each purpose has separate desire lines.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Enum, Serialize, Deserialize,
+)]
+pub enum TripPurpose {
+    Commute,
+    School,
+    Utility,
+    Leisure,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ODOptions {
+    /// How many trips to route for each desire line. The desire line's count is split evenly
+    /// between them.
+    pub samples_per_desire_line: usize,
+    pub sampling: Sampling,
+    /// The same seed always produces the same trips
+    pub seed: u64,
+    /// How to turn routed trips into cycling uptake
+    pub scenario: Scenario,
+    /// What to compare route lengths against
+    pub directness_baseline: DirectnessBaseline,
+    /// Also keep results for every desire line. This is slower and uses more memory.
+    pub per_desire_line: bool,
+}
+
+impl Default for ODOptions {
+    fn default() -> Self {
+        Self {
+            samples_per_desire_line: 1,
+            sampling: Sampling::PopulationWeighted,
+            seed: 42,
+            scenario: Scenario::GoDutch,
+            directness_baseline: DirectnessBaseline::CarRoute,
+            per_desire_line: false,
+        }
+    }
+}
 
-        let mut rng = WyRand::new_seed(42);
+/// How to pick the endpoints of trips within a zone
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Sampling {
+    /// Split the zone into strips and pick one point in each, so multiple samples spread out
+    Stratified,
+    /// Pick data zones overlapping the zone, weighted by their population
+    PopulationWeighted,
+    /// Pick a point along a road in the zone, weighted by road length
+    RoadNetwork,
+}
 
-        let mut counts = HashMap::new();
-        let mut succeeded = 0;
-        let mut failed = 0;
-        let mut sum_directness = 0.0;
-        let mut sum_count = 0.0;
+/// How well one desire line is served, averaged over its routed trips
+#[derive(Clone)]
+pub struct DesireLineResult {
+    pub purpose: TripPurpose,
+    pub zone1: String,
+    pub zone2: String,
+    /// The number of trips in the input, before uptake
+    pub count: usize,
+    /// How many sampled trips could be routed
+    pub succeeded: usize,
+    /// In meters
+    pub route_length: f64,
+    /// In meters, the length of the directness baseline, only over trips where it exists
+    pub baseline_length: Option<f64>,
+    /// The routed length divided by the length of the directness baseline
+    pub directness: Option<f64>,
+    /// Between 0 and 1
+    pub uptake: f64,
+    /// The fraction of the routed length on each level of service and infrastructure type
+    pub los: EnumMap<LevelOfService, f64>,
+    pub infra_type: EnumMap<InfraType, f64>,
+}
 
-        let mut worst_directness_routes = Vec::new();
+/// Sums over the routed trips for one desire line
+#[derive(Default)]
+struct DesireLineSums {
+    succeeded: usize,
+    route_length: f64,
+    baseline_length: f64,
+    directness: f64,
+    directness_trips: usize,
+    uptake: f64,
+    los: EnumMap<LevelOfService, f64>,
+    infra_type: EnumMap<InfraType, f64>,
+}
 
-        info!("Evaluating {} desire lines", self.desire_lines.len());
+impl MapModel {
+    pub fn od_counts(&self, opts: &ODOptions) -> Result<CountsOD> {
+        let samples = opts.samples_per_desire_line.max(1);
 
-        // TODO TEMPORARILY, evaluate just one route from each desire line and weight it by the
-        // count
-        for (zone1, zone2, raw_count) in &self.desire_lines {
-            let pt1 = self.od_zones[zone1].random_point(&mut rng);
-            let pt2 = self.od_zones[zone2].random_point(&mut rng);
+        let zone_weights = self.zone_weights(opts.sampling);
 
-            let profile = self.graph.profile_names["bicycle"];
-            let start = self.graph.snap_to_road(pt1, profile);
-            let end = self.graph.snap_to_road(pt2, profile);
-            let Ok(route) = self.graph.routers[profile.0].route(&self.graph, start, end) else {
-                failed += 1;
-                continue;
-            };
-            succeeded += 1;
-
-            // TODO Still deciding which to use
-            let compare_length = if true {
-                // Compare with the car route
-                let car_profile = self.graph.profile_names["car"];
-                let car_start = self.graph.snap_to_road(pt1, car_profile);
-                let car_end = self.graph.snap_to_road(pt2, car_profile);
-                if let Ok(car_route) =
-                    self.graph.routers[car_profile.0].route(&self.graph, car_start, car_end)
-                {
-                    let mut car_length = 0.0;
-                    for step in &car_route.steps {
-                        if let PathStep::Road { road, .. } = step {
-                            car_length += self.graph.roads[road.0].length_meters;
-                        }
+        let mut combined = CountsAccumulator::default();
+        let mut per_purpose = BTreeMap::new();
+        for (purpose, desire_lines) in &self.desire_lines {
+            info!(
+                "Evaluating {} {purpose:?} desire lines, with {samples} trips each",
+                desire_lines.len()
+            );
+            let model = opts.scenario.model(*purpose);
+
+            let route_chunk = |chunk: &[DesireLine]| {
+                let mut acc = CountsAccumulator::default();
+                for desire_line in chunk {
+                    let mut sums = opts.per_desire_line.then(DesireLineSums::default);
+                    let current_cycling = desire_line.current_cycling();
+                    for (pt1, pt2, count) in
+                        self.sample_trips(*purpose, desire_line, opts, &zone_weights)
+                    {
+                        self.route_trip(
+                            &model,
+                            pt1,
+                            pt2,
+                            count,
+                            current_cycling,
+                            opts,
+                            &mut acc,
+                            sums.as_mut(),
+                        );
+                    }
+                    if let Some(sums) = sums {
+                        acc.desire_lines.push(sums.finish(*purpose, desire_line));
                     }
-                    car_length
-                } else {
-                    // Skip this one
-                    0.0
                 }
-            } else {
-                // Straight line distance.
-                Euclidean::distance(
-                    self.graph.intersections[start.intersection.0].point,
-                    self.graph.intersections[end.intersection.0].point,
-                )
+                acc
             };
 
-            // TODO route.linestring() is more accurate, but slower, and then we have to do the
-            // same for comparisons
-            let mut route_length = 0.0;
-            // TODO Use a lower-level API to squeeze out some speed
+            let acc = route_in_chunks(desire_lines, route_chunk);
+            combined.merge(&acc);
+            per_purpose.insert(*purpose, acc.finish(&self.graph.mercator, BTreeMap::new()));
+        }
+
+        Ok(combined.finish(&self.graph.mercator, per_purpose))
+    }
+
+    /// Route one sampled trip and add its uptake to the counts, and optionally to the sums for
+    /// its desire line
+    #[allow(clippy::too_many_arguments)]
+    fn route_trip(
+        &self,
+        model: &UptakeModel,
+        pt1: Coord,
+        pt2: Coord,
+        count_per_sample: f64,
+        current_cycling: f64,
+        opts: &ODOptions,
+        acc: &mut CountsAccumulator,
+        sums: Option<&mut DesireLineSums>,
+    ) {
+        let profile = self.graph.profile_names["bicycle"];
+        let start = self.graph.snap_to_road(pt1, profile);
+        let end = self.graph.snap_to_road(pt2, profile);
+        let Ok(route) = self.graph.routers[profile.0].route(&self.graph, start, end) else {
+            acc.failed += 1;
+            return;
+        };
+        acc.succeeded += 1;
+
+        // Uptake and directness both use the length between the snapped positions
+        let (route_length, average_gradient) = self.route_length_and_gradient(&route);
+
+        let uptake = model.uptake(route_length, average_gradient, current_cycling);
+        let count = uptake * count_per_sample;
+
+        let baseline_length = self
+            .directness_baseline_length(opts.directness_baseline, start, end)
+            .filter(|x| *x > 0.0);
+        let directness = baseline_length.map(|x| route_length / x);
+
+        if let Some(sums) = sums {
+            sums.succeeded += 1;
+            sums.route_length += route_length;
+            sums.uptake += uptake;
+            if let (Some(baseline_length), Some(directness)) = (baseline_length, directness) {
+                sums.baseline_length += baseline_length;
+                sums.directness += directness;
+                sums.directness_trips += 1;
+            }
             for step in &route.steps {
                 if let PathStep::Road { road, .. } = step {
-                    route_length += self.graph.roads[road.0].length_meters;
+                    let length = self.graph.roads[road.0].length_meters;
+                    sums.los[self.los[road.0]] += length;
+                    sums.infra_type[self.get_infra_type(*road)] += length;
                 }
             }
+        }
+
+        for step in route.steps {
+            if let PathStep::Road { road, .. } = step {
+                *acc.counts.entry(road).or_insert(0.0) += count;
+            }
+        }
 
-            let count = uptake::pct_godutch_2020(route_length) * (*raw_count as f64);
+        if let Some(directness) = directness {
+            acc.sum_directness += count * directness;
+            acc.sum_count += count;
+            acc.add_directness_route(pt1, pt2, directness);
+        }
+    }
 
-            for step in route.steps {
-                if let PathStep::Road { road, .. } = step {
-                    *counts.entry(road).or_insert(0.0) += count;
-                }
+    /// Returns the length of a route in meters between its snapped positions, and the
+    /// length-weighted average of the absolute gradient along it, as a percent
+    pub(crate) fn route_length_and_gradient(&self, route: &Route) -> (f64, f64) {
+        let mut route_length = 0.0;
+        // Uphill and downhill both count, like stplanr's route_average_gradient
+        let mut sum_gradient = 0.0;
+        for (road, length) in self.route_road_lengths(route) {
+            route_length += length;
+            sum_gradient += length * self.gradients[road.0].abs();
+        }
+        let average_gradient = if route_length > 0.0 {
+            sum_gradient / route_length
+        } else {
+            0.0
+        };
+        (route_length, average_gradient)
+    }
+
+    /// Pick the start and end of each trip to route for one desire line, along with the count
+    /// that each trip represents
+    pub(crate) fn sample_trips(
+        &self,
+        purpose: TripPurpose,
+        desire_line: &DesireLine,
+        opts: &ODOptions,
+        zone_weights: &HashMap<String, Vec<(usize, usize)>>,
+    ) -> Vec<(Coord, Coord, f64)> {
+        let (zone1, zone2) = (desire_line.zone1.as_str(), desire_line.zone2.as_str());
+        let samples = opts.samples_per_desire_line.max(1);
+        // Seed each desire line separately, so the trips don't depend on the order, presence of
+        // other desire lines, or which thread handles them
+        let mut rng = WyRand::new_seed(desire_line_seed(opts.seed, purpose, zone1, zone2));
+        let starts = self.sample_points(zone1, samples, opts.sampling, zone_weights, &mut rng);
+        let ends = if zone1 == zone2 {
+            // Two independent points in the same zone are an arbitrary pair, often crossing the
+            // whole zone. Model these as short trips around the start instead.
+            let zone = &self.od_zones[zone1];
+            starts
+                .iter()
+                .map(|pt| zone.intrazonal_destination(*pt, &mut rng))
+                .collect()
+        } else {
+            self.sample_points(zone2, samples, opts.sampling, zone_weights, &mut rng)
+        };
+        let count_per_sample = (desire_line.all as f64) / (samples as f64);
+        starts
+            .into_iter()
+            .zip(ends)
+            .map(|(pt1, pt2)| (pt1, pt2, count_per_sample))
+            .collect()
+    }
+
+    /// Pick `n` points in a zone
+    fn sample_points(
+        &self,
+        zone_name: &str,
+        n: usize,
+        sampling: Sampling,
+        zone_weights: &HashMap<String, Vec<(usize, usize)>>,
+        rng: &mut WyRand,
+    ) -> Vec<Coord> {
+        let zone = &self.od_zones[zone_name];
+        match sampling {
+            Sampling::Stratified => (0..n)
+                .map(|idx| zone.random_point_in_strip(idx, n, rng))
+                .collect(),
+            Sampling::PopulationWeighted | Sampling::RoadNetwork => {
+                let weights = zone_weights
+                    .get(zone_name)
+                    .map(|x| x.as_slice())
+                    .unwrap_or(&[]);
+                (0..n)
+                    .map(|_| {
+                        let Some(idx) = weighted_pick(weights, rng) else {
+                            return zone.random_point(rng);
+                        };
+                        if let Sampling::PopulationWeighted = sampling {
+                            zone.random_point_within(&self.data_zones[idx].polygon, rng)
+                        } else {
+                            zone.random_point_along(&self.graph.roads[idx].linestring, rng)
+                        }
+                    })
+                    .collect()
             }
+        }
+    }
 
-            if compare_length > 0.0 {
-                let directness = route_length / compare_length;
-                sum_directness += count * directness;
-                sum_count += count;
-
-                if worst_directness_routes.len() < keep_directness_routes {
-                    worst_directness_routes.push((pt1, pt2, directness));
-                    worst_directness_routes.sort_by_key(|(_, _, d)| (*d * -100.0) as isize);
-                } else if worst_directness_routes.last().as_ref().unwrap().2 < directness {
-                    worst_directness_routes.pop();
-                    worst_directness_routes.push((pt1, pt2, directness));
-                    worst_directness_routes.sort_by_key(|(_, _, d)| (*d * -100.0) as isize);
+    /// For every OD zone, find the weights used to sample points. For population-weighted
+    /// sampling, these are overlapping data zones and their population. For road network
+    /// sampling, these are roads and their length in meters.
+    pub(crate) fn zone_weights(&self, sampling: Sampling) -> HashMap<String, Vec<(usize, usize)>> {
+        let mut result: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+        match sampling {
+            Sampling::Stratified => {}
+            Sampling::PopulationWeighted => {
+                for (name, zone) in &self.od_zones {
+                    let mut weights = Vec::new();
+                    for (idx, data_zone) in self.data_zones.iter().enumerate() {
+                        if data_zone.population > 0 && zone.mp.intersects(&data_zone.polygon) {
+                            weights.push((idx, data_zone.population));
+                        }
+                    }
+                    result.insert(name.clone(), weights);
+                }
+            }
+            Sampling::RoadNetwork => {
+                let profile = self.graph.profile_names["bicycle"];
+                // Zones may overlap slightly, so check them in a fixed order to always pick the
+                // same one
+                let mut zones: Vec<(&String, &Zone)> = self.od_zones.iter().collect();
+                zones.sort_by_key(|(name, _)| *name);
+                for (idx, road) in self.graph.roads.iter().enumerate() {
+                    if road.access[profile.0] == Direction::None {
+                        continue;
+                    }
+                    // Assign each road to one zone by its middle
+                    let Some(pt) = road.linestring.line_interpolate_point(0.5) else {
+                        continue;
+                    };
+                    if let Some((name, _)) = zones
+                        .iter()
+                        .find(|(_, zone)| zone.contains_bbox(pt.into()) && zone.mp.contains(&pt))
+                    {
+                        result
+                            .entry(name.to_string())
+                            .or_default()
+                            .push((idx, road.length_meters.ceil() as usize));
+                    }
                 }
             }
         }
-
-        Ok(CountsOD {
-            // Round count after summing decimals
-            counts: counts
-                .into_iter()
-                .map(|(k, v)| (k, v.round() as usize))
-                .collect(),
-            succeeded,
-            failed,
-            average_weighted_directness: sum_directness / (sum_count as f64),
-            worst_directness_routes: worst_directness_routes
-                .into_iter()
-                .map(|(start, end, _)| {
-                    (
-                        self.graph.mercator.pt_to_wgs84(start),
-                        self.graph.mercator.pt_to_wgs84(end),
-                    )
-                })
-                .collect(),
-        })
+        result
     }
 
-    /// Returns detailed GJ with per-road counts
-    pub fn evaluate_od(&self) -> Result<String> {
-        let out = self.od_counts()?;
+    /// Draws each desire line between the centroids of its zones
+    pub fn desire_lines_to_geojson(&self, results: &[DesireLineResult]) -> Result<String> {
+        let mut features = Vec::new();
+        for result in results {
+            let (Some(pt1), Some(pt2)) = (
+                self.od_zones[&result.zone1].mp.centroid(),
+                self.od_zones[&result.zone2].mp.centroid(),
+            ) else {
+                continue;
+            };
+            let mut f = self
+                .graph
+                .mercator
+                .to_wgs84_gj(&LineString::new(vec![pt1.into(), pt2.into()]));
+            if let serde_json::Value::Object(props) = serde_json::to_value(result.to_record())? {
+                f.properties = Some(props);
+            }
+            features.push(f);
+        }
+        Ok(serde_json::to_string(&FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: None,
+        })?)
+    }
 
-        let mut count_by_infra: EnumMap<InfraType, usize> = EnumMap::default();
-        let mut count_off_network = 0;
-        let mut total_count = 0;
+    /// Returns detailed GJ with per-road counts, combined and per trip purpose
+    pub fn evaluate_od(&mut self, timer: &mut Timer, opts: &ODOptions) -> Result<String> {
+        let out = self.cached_od_counts(timer, opts)?;
 
         let mut max_count = 0;
         let mut features = Vec::new();
-        for (r, count) in out.counts {
-            max_count = max_count.max(count);
+        for (r, count) in &out.counts {
+            max_count = max_count.max(*count);
             let mut f = self
                 .graph
                 .mercator
                 .to_wgs84_gj(&self.graph.roads[r.0].linestring);
-            f.set_property("count", count);
+            f.set_property("count", *count);
+            for (purpose, purpose_out) in &out.per_purpose {
+                f.set_property(
+                    format!("count_{purpose:?}").to_lowercase(),
+                    purpose_out.counts.get(r).cloned().unwrap_or(0),
+                );
+            }
             f.set_property(
                 "infra_type",
-                serde_json::to_value(self.get_infra_type(r)).unwrap(),
+                serde_json::to_value(self.get_infra_type(*r)).unwrap(),
             );
             features.push(f);
+        }
+
+        let mut foreign_members = self.summarize_od_counts(&out);
+        foreign_members.insert("max_count".to_string(), max_count.into());
+        foreign_members.insert("scenario".to_string(), serde_json::to_value(opts.scenario)?);
+        foreign_members.insert(
+            "directness_baseline".to_string(),
+            serde_json::to_value(opts.directness_baseline)?,
+        );
+        let mut purposes = serde_json::Map::new();
+        for (purpose, purpose_out) in &out.per_purpose {
+            purposes.insert(
+                format!("{purpose:?}"),
+                serde_json::Value::Object(self.summarize_od_counts(purpose_out)),
+            );
+        }
+        foreign_members.insert("purposes".to_string(), serde_json::Value::Object(purposes));
 
+        Ok(serde_json::to_string(&FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: Some(foreign_members),
+        })?)
+    }
+
+    fn summarize_od_counts(&self, out: &CountsOD) -> serde_json::Map<String, serde_json::Value> {
+        let mut count_by_infra: EnumMap<InfraType, usize> = EnumMap::default();
+        let mut count_off_network = 0;
+        let mut total_count = 0;
+        for (r, count) in &out.counts {
             total_count += count;
             if let Some(infra_type) = self.infra_types[r.0] {
                 count_by_infra[infra_type] += count;
@@ -165,10 +482,9 @@ impl MapModel {
             }
         }
 
-        let mut foreign_members = serde_json::json!({
+        let mut summary = serde_json::json!({
             "succeeded": out.succeeded,
             "failed": out.failed,
-            "max_count": max_count,
             "percent_off_network": percent(count_off_network, total_count),
         })
         .as_object()
@@ -181,16 +497,11 @@ impl MapModel {
                 percent(count, total_count).into(),
             );
         }
-        foreign_members.insert(
+        summary.insert(
             "percent_on_network".to_string(),
             serde_json::Value::Object(percent_on_network),
         );
-
-        Ok(serde_json::to_string(&FeatureCollection {
-            features,
-            bbox: None,
-            foreign_members: Some(foreign_members),
-        })?)
+        summary
     }
 }
 
@@ -205,8 +516,10 @@ pub struct Zone {
 }
 
 impl Zone {
+    /// Pick any point in the zone, without weighting. If the zone is degenerate, use its
+    /// center.
     fn random_point(&self, rng: &mut WyRand) -> Coord {
-        loop {
+        for _ in 0..MAX_ATTEMPTS {
             let x = (rng.generate_range(self.x1..=self.x2) as f64) / 100.0;
             let y = (rng.generate_range(self.y1..=self.y2) as f64) / 100.0;
             let pt = Coord { x, y };
@@ -214,6 +527,92 @@ impl Zone {
                 return pt;
             }
         }
+        Coord {
+            x: ((self.x1 + self.x2) as f64) / 200.0,
+            y: ((self.y1 + self.y2) as f64) / 200.0,
+        }
+    }
+
+    fn contains_bbox(&self, pt: Coord) -> bool {
+        let (x, y) = ((pt.x * 100.0) as i64, (pt.y * 100.0) as i64);
+        self.x1 <= x && x <= self.x2 && self.y1 <= y && y <= self.y2
+    }
+
+    /// Pick a point along part of the linestring inside the zone
+    fn random_point_along(&self, linestring: &LineString, rng: &mut WyRand) -> Coord {
+        for _ in 0..MAX_ATTEMPTS {
+            let fraction = (rng.generate_range(0..=1000_u32) as f64) / 1000.0;
+            if let Some(pt) = linestring.line_interpolate_point(fraction) {
+                if self.mp.contains(&pt) {
+                    return pt.into();
+                }
+            }
+        }
+        self.random_point(rng)
+    }
+
+    /// For a trip starting and ending in this zone, pick a destination a short distance from the
+    /// start, in a random direction. The distance is at most the radius of a circle with the
+    /// zone's area.
+    fn intrazonal_destination(&self, start: Coord, rng: &mut WyRand) -> Coord {
+        let max_distance = (self.mp.unsigned_area() / std::f64::consts::PI)
+            .sqrt()
+            .max(MIN_INTRAZONAL_DISTANCE);
+        for _ in 0..MAX_ATTEMPTS {
+            let distance = MIN_INTRAZONAL_DISTANCE
+                + (max_distance - MIN_INTRAZONAL_DISTANCE)
+                    * ((rng.generate_range(0..=1000_u32) as f64) / 1000.0);
+            let angle = (rng.generate_range(0..360_u32) as f64).to_radians();
+            let pt = Coord {
+                x: start.x + distance * angle.cos(),
+                y: start.y + distance * angle.sin(),
+            };
+            if self.mp.contains(&pt) {
+                return pt;
+            }
+        }
+        // Give up and use any point, even if it's far away
+        self.random_point(rng)
+    }
+
+    /// Divide the zone into `n` vertical strips and pick a point in the `idx`th one. If the strip
+    /// doesn't overlap the zone much, fall back to anywhere in the zone.
+    fn random_point_in_strip(&self, idx: usize, n: usize, rng: &mut WyRand) -> Coord {
+        let width = (self.x2 - self.x1) / (n as i64);
+        let x1 = self.x1 + width * (idx as i64);
+        let x2 = if idx == n - 1 { self.x2 } else { x1 + width };
+        for _ in 0..MAX_ATTEMPTS {
+            let x = (rng.generate_range(x1..=x2) as f64) / 100.0;
+            let y = (rng.generate_range(self.y1..=self.y2) as f64) / 100.0;
+            let pt = Coord { x, y };
+            if self.mp.contains(&pt) {
+                return pt;
+            }
+        }
+        self.random_point(rng)
+    }
+
+    /// Pick a point inside both the zone and the polygon. If they barely overlap, fall back to
+    /// anywhere in the zone.
+    fn random_point_within(&self, polygon: &MultiPolygon, rng: &mut WyRand) -> Coord {
+        let Some(bbox) = polygon.bounding_rect() else {
+            return self.random_point(rng);
+        };
+        let x1 = ((bbox.min().x * 100.0) as i64).max(self.x1);
+        let y1 = ((bbox.min().y * 100.0) as i64).max(self.y1);
+        let x2 = ((bbox.max().x * 100.0) as i64).min(self.x2);
+        let y2 = ((bbox.max().y * 100.0) as i64).min(self.y2);
+        if x1 < x2 && y1 < y2 {
+            for _ in 0..MAX_ATTEMPTS {
+                let x = (rng.generate_range(x1..=x2) as f64) / 100.0;
+                let y = (rng.generate_range(y1..=y2) as f64) / 100.0;
+                let pt = Coord { x, y };
+                if self.mp.contains(&pt) && polygon.contains(&pt) {
+                    return pt;
+                }
+            }
+        }
+        self.random_point(rng)
     }
 
     pub fn parse_zones(
@@ -260,6 +659,235 @@ impl Zone {
     }
 }
 
+// For rejection sampling
+const MAX_ATTEMPTS: usize = 100;
+// In meters
+const MIN_INTRAZONAL_DISTANCE: f64 = 250.0;
+
+/// Pick an index, with probability proportional to its weight. None if there are no weights.
+fn weighted_pick(weights: &[(usize, usize)], rng: &mut WyRand) -> Option<usize> {
+    let total: usize = weights.iter().map(|(_, weight)| *weight).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut pick = rng.generate_range(0..total);
+    for (idx, weight) in weights {
+        if pick < *weight {
+            return Some(*idx);
+        }
+        pick -= *weight;
+    }
+    unreachable!()
+}
+
+/// Combine everything identifying a desire line into one seed. This is 64-bit FNV-1a, not one of
+/// std's hashers, because those may change between Rust releases.
+fn desire_line_seed(seed: u64, purpose: TripPurpose, zone1: &str, zone2: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut add = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+    add(&seed.to_le_bytes());
+    add(&[purpose as u8]);
+    // Separate the names, so ("ab", "c") and ("a", "bc") differ
+    add(zone1.as_bytes());
+    add(&[0xff]);
+    add(zone2.as_bytes());
+    hash
+}
+
+/// Desire lines are routed in chunks of this size, possibly on different threads
+const DESIRE_LINES_PER_CHUNK: usize = 100;
+
+/// Route chunks of desire lines, on multiple threads if the `parallel` feature is enabled. Chunks
+/// are always merged in the same order, so the floating point sums are the same with or without
+/// threads.
+fn route_in_chunks<T: Sync>(
+    desire_lines: &[T],
+    route_chunk: impl Fn(&[T]) -> CountsAccumulator + Sync,
+) -> CountsAccumulator {
+    let mut acc = CountsAccumulator::default();
+    #[cfg(feature = "parallel")]
+    {
+        let chunks: Vec<CountsAccumulator> = desire_lines
+            .par_chunks(DESIRE_LINES_PER_CHUNK)
+            .map(&route_chunk)
+            .collect();
+        for chunk in chunks {
+            acc.merge(&chunk);
+        }
+    }
+    #[cfg(not(feature = "parallel"))]
+    for chunk in desire_lines.chunks(DESIRE_LINES_PER_CHUNK) {
+        acc.merge(&route_chunk(chunk));
+    }
+    acc
+}
+
+/// Sums up routed trips, before rounding counts
+#[derive(Default)]
+struct CountsAccumulator {
+    counts: HashMap<RoadID, f64>,
+    succeeded: usize,
+    failed: usize,
+    sum_directness: f64,
+    sum_count: f64,
+    // Sorted with the least direct first
+    worst_directness_routes: Vec<(Coord, Coord, f64)>,
+    desire_lines: Vec<DesireLineResult>,
+}
+
+impl CountsAccumulator {
+    const KEEP_DIRECTNESS_ROUTES: usize = 10;
+
+    fn add_directness_route(&mut self, pt1: Coord, pt2: Coord, directness: f64) {
+        if self.worst_directness_routes.len() < Self::KEEP_DIRECTNESS_ROUTES {
+            self.worst_directness_routes.push((pt1, pt2, directness));
+        } else if self.worst_directness_routes.last().unwrap().2 < directness {
+            self.worst_directness_routes.pop();
+            self.worst_directness_routes.push((pt1, pt2, directness));
+        } else {
+            return;
+        }
+        self.worst_directness_routes
+            .sort_by_key(|(_, _, d)| (*d * -100.0) as isize);
+    }
+
+    fn merge(&mut self, other: &CountsAccumulator) {
+        for (r, count) in &other.counts {
+            *self.counts.entry(*r).or_insert(0.0) += *count;
+        }
+        self.succeeded += other.succeeded;
+        self.failed += other.failed;
+        self.sum_directness += other.sum_directness;
+        self.sum_count += other.sum_count;
+        for (pt1, pt2, directness) in &other.worst_directness_routes {
+            self.add_directness_route(*pt1, *pt2, *directness);
+        }
+        self.desire_lines.extend(other.desire_lines.iter().cloned());
+    }
+
+    fn finish(self, mercator: &Mercator, per_purpose: BTreeMap<TripPurpose, CountsOD>) -> CountsOD {
+        CountsOD {
+            // Round count after summing decimals
+            counts: self
+                .counts
+                .into_iter()
+                .map(|(k, v)| (k, v.round() as usize))
+                .collect(),
+            succeeded: self.succeeded,
+            failed: self.failed,
+            average_weighted_directness: self.sum_directness / self.sum_count,
+            worst_directness_routes: self
+                .worst_directness_routes
+                .into_iter()
+                .map(|(start, end, _)| (mercator.pt_to_wgs84(start), mercator.pt_to_wgs84(end)))
+                .collect(),
+            desire_lines: self.desire_lines,
+            per_purpose,
+        }
+    }
+}
+
+/// One desire line's results as flat columns, for CSV or GeoJSON output
+#[derive(Default, Serialize)]
+pub struct DesireLineRecord {
+    purpose: String,
+    zone1: String,
+    zone2: String,
+    count: usize,
+    succeeded: usize,
+    route_length: f64,
+    baseline_length: Option<f64>,
+    directness: Option<f64>,
+    uptake: f64,
+    #[serde(rename = "los_High")]
+    los_high: f64,
+    #[serde(rename = "los_Medium")]
+    los_medium: f64,
+    #[serde(rename = "los_Low")]
+    los_low: f64,
+    #[serde(rename = "los_ShouldNotBeUsed")]
+    los_should_not_be_used: f64,
+    #[serde(rename = "infra_type_SegregatedWide")]
+    infra_type_segregated_wide: f64,
+    #[serde(rename = "infra_type_OffRoad")]
+    infra_type_off_road: f64,
+    #[serde(rename = "infra_type_SegregatedNarrow")]
+    infra_type_segregated_narrow: f64,
+    #[serde(rename = "infra_type_SharedFootway")]
+    infra_type_shared_footway: f64,
+    #[serde(rename = "infra_type_CycleLane")]
+    infra_type_cycle_lane: f64,
+    #[serde(rename = "infra_type_MixedTraffic")]
+    infra_type_mixed_traffic: f64,
+    #[serde(rename = "infra_type_Unknown")]
+    infra_type_unknown: f64,
+}
+
+impl DesireLineResult {
+    pub fn to_record(&self) -> DesireLineRecord {
+        DesireLineRecord {
+            purpose: format!("{:?}", self.purpose),
+            zone1: self.zone1.clone(),
+            zone2: self.zone2.clone(),
+            count: self.count,
+            succeeded: self.succeeded,
+            route_length: self.route_length,
+            baseline_length: self.baseline_length,
+            directness: self.directness,
+            uptake: self.uptake,
+            los_high: self.los[LevelOfService::High],
+            los_medium: self.los[LevelOfService::Medium],
+            los_low: self.los[LevelOfService::Low],
+            los_should_not_be_used: self.los[LevelOfService::ShouldNotBeUsed],
+            infra_type_segregated_wide: self.infra_type[InfraType::SegregatedWide],
+            infra_type_off_road: self.infra_type[InfraType::OffRoad],
+            infra_type_segregated_narrow: self.infra_type[InfraType::SegregatedNarrow],
+            infra_type_shared_footway: self.infra_type[InfraType::SharedFootway],
+            infra_type_cycle_lane: self.infra_type[InfraType::CycleLane],
+            infra_type_mixed_traffic: self.infra_type[InfraType::MixedTraffic],
+            infra_type_unknown: self.infra_type[InfraType::Unknown],
+        }
+    }
+}
+
+impl DesireLineSums {
+    fn finish(self, purpose: TripPurpose, desire_line: &DesireLine) -> DesireLineResult {
+        let trips = self.succeeded.max(1) as f64;
+        let total_length: f64 = self.los.values().sum();
+        let mut los = self.los;
+        let mut infra_type = self.infra_type;
+        if total_length > 0.0 {
+            for (_, x) in &mut los {
+                *x /= total_length;
+            }
+            for (_, x) in &mut infra_type {
+                *x /= total_length;
+            }
+        }
+
+        DesireLineResult {
+            purpose,
+            zone1: desire_line.zone1.clone(),
+            zone2: desire_line.zone2.clone(),
+            count: desire_line.all,
+            succeeded: self.succeeded,
+            route_length: self.route_length / trips,
+            baseline_length: (self.directness_trips > 0)
+                .then(|| self.baseline_length / self.directness_trips as f64),
+            directness: (self.directness_trips > 0)
+                .then(|| self.directness / self.directness_trips as f64),
+            uptake: self.uptake / trips,
+            los,
+            infra_type,
+        }
+    }
+}
+
 fn percent(x: usize, total: usize) -> f64 {
     if total == 0 {
         0.0
@@ -267,3 +895,120 @@ fn percent(x: usize, total: usize) -> f64 {
         (x as f64) / (total as f64)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_desire_line_seed() {
+        // Pinned, so sampled points stay the same across toolchains. Checked against an
+        // independent FNV-1a implementation.
+        let mut ok = true;
+        for (seed, purpose, zone1, zone2, expected) in [
+            (
+                42,
+                TripPurpose::Commute,
+                "S02001234",
+                "S02001235",
+                11684343132041660067,
+            ),
+            (
+                42,
+                TripPurpose::School,
+                "S02001234",
+                "S02001235",
+                5966274892843296766,
+            ),
+            (0, TripPurpose::Commute, "ab", "c", 16110415603094501666),
+            (0, TripPurpose::Commute, "a", "bc", 10402478017848492652),
+        ] {
+            let actual = desire_line_seed(seed, purpose, zone1, zone2);
+            if actual != expected {
+                println!(
+                    "For {purpose:?} from {zone1} to {zone2}, expected {expected} but got {actual}"
+                );
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+
+    #[test]
+    fn test_route_in_chunks() {
+        // Run with and without --features parallel. Either way, the result must match routing
+        // the same chunks one at a time, in order.
+        let desire_lines: Vec<usize> = (0..(2 * DESIRE_LINES_PER_CHUNK + 37)).collect();
+        // Stands in for routing. The counts and directness don't add up exactly as floats, so
+        // merging in a different order would change the sums.
+        let route_chunk = |chunk: &[usize]| {
+            let mut acc = CountsAccumulator::default();
+            for i in chunk {
+                let count = (*i as f64) / 3.0 + 0.1;
+                *acc.counts.entry(RoadID(i % 7)).or_insert(0.0) += count;
+                if i % 11 == 0 {
+                    acc.failed += 1;
+                    continue;
+                }
+                acc.succeeded += 1;
+                let directness = 1.0 + ((i * 37) % 101) as f64 / 7.0;
+                acc.sum_directness += count * directness;
+                acc.sum_count += count;
+                let pt = Coord {
+                    x: *i as f64,
+                    y: 0.0,
+                };
+                acc.add_directness_route(pt, pt, directness);
+            }
+            acc
+        };
+
+        let mut expected = CountsAccumulator::default();
+        for chunk in desire_lines.chunks(DESIRE_LINES_PER_CHUNK) {
+            expected.merge(&route_chunk(chunk));
+        }
+        let actual = route_in_chunks(&desire_lines, route_chunk);
+        // Routing everything as one chunk sums in a different order, so only the rounded counts
+        // are the same
+        let single = route_chunk(&desire_lines);
+
+        let mut ok = true;
+        let rounded = |acc: &CountsAccumulator| -> Vec<(usize, usize)> {
+            let mut counts: Vec<(usize, usize)> = acc
+                .counts
+                .iter()
+                .map(|(r, count)| (r.0, count.round() as usize))
+                .collect();
+            counts.sort();
+            counts
+        };
+        for (description, matches) in [
+            ("counts", actual.counts == expected.counts),
+            ("rounded counts", rounded(&single) == rounded(&actual)),
+            ("succeeded", actual.succeeded == expected.succeeded),
+            ("failed", actual.failed == expected.failed),
+            (
+                "sum_directness",
+                actual.sum_directness == expected.sum_directness,
+            ),
+            ("sum_count", actual.sum_count == expected.sum_count),
+            (
+                "worst_directness_routes",
+                actual.worst_directness_routes == expected.worst_directness_routes
+                    && single.worst_directness_routes == expected.worst_directness_routes,
+            ),
+        ] {
+            if !matches {
+                println!("Routing in chunks changed {description}");
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+}
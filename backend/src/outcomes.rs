@@ -0,0 +1,288 @@
+use anyhow::Result;
+use graph::{PathStep, RoadID, Route, Timer};
+use serde::Deserialize;
+
+use crate::{
+    directness::DirectnessBaseline, od::ODOptions, uptake::Scenario, LevelOfService, MapModel,
+};
+
+/// All of the assumptions used to turn cycling uptake into outcomes. The defaults are rough UK
+/// figures; override them for a particular study.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct OutcomesConfig {
+    /// The scenario's uptake is only reached on routes with a high level of service. These scale
+    /// the increase over the baseline scenario for the length of a route at each level.
+    pub realised_high_los: f64,
+    pub realised_medium_los: f64,
+    pub realised_low_los: f64,
+    pub realised_should_not_be_used_los: f64,
+
+    /// How many one-way trips each person on a desire line makes per year
+    pub trips_per_year: f64,
+    /// The fraction of new cycle trips that would otherwise be driven
+    pub car_mode_shift: f64,
+    /// Average car emissions, from the UK government's greenhouse gas conversion factors
+    pub co2_kg_per_car_km: f64,
+
+    /// Used to turn distance cycled into time for the health model
+    pub cycling_speed_kmh: f64,
+    /// From WHO's HEAT: the relative risk of death for someone cycling the reference volume
+    pub heat_relative_risk: f64,
+    pub heat_reference_minutes_per_week: f64,
+    /// HEAT caps the reduction in risk, since benefits level off
+    pub heat_max_risk_reduction: f64,
+    /// Deaths per year per person, for adults of working age
+    pub mortality_rate: f64,
+    /// In pounds
+    pub value_of_statistical_life: f64,
+}
+
+impl Default for OutcomesConfig {
+    fn default() -> Self {
+        Self {
+            // TODO Just making these up for now!
+            realised_high_los: 1.0,
+            realised_medium_los: 0.5,
+            realised_low_los: 0.1,
+            realised_should_not_be_used_los: 0.0,
+
+            // A return trip on 220 days
+            trips_per_year: 440.0,
+            car_mode_shift: 0.5,
+            co2_kg_per_car_km: 0.17,
+
+            cycling_speed_kmh: 15.0,
+            heat_relative_risk: 0.9,
+            heat_reference_minutes_per_week: 100.0,
+            heat_max_risk_reduction: 0.45,
+            mortality_rate: 0.0032,
+            value_of_statistical_life: 2_000_000.0,
+        }
+    }
+}
+
+impl OutcomesConfig {
+    fn realised(&self, los: LevelOfService) -> f64 {
+        match los {
+            LevelOfService::High => self.realised_high_los,
+            LevelOfService::Medium => self.realised_medium_los,
+            LevelOfService::Low => self.realised_low_los,
+            LevelOfService::ShouldNotBeUsed => self.realised_should_not_be_used_los,
+        }
+    }
+
+    /// The reduction in the risk of death for someone regularly cycling a one-way trip
+    fn risk_reduction(&self, trip_km: f64) -> f64 {
+        let minutes_per_week = trip_km / self.cycling_speed_kmh * 60.0 * self.trips_per_year / 52.0;
+        ((1.0 - self.heat_relative_risk) * minutes_per_week / self.heat_reference_minutes_per_week)
+            .min(self.heat_max_risk_reduction)
+    }
+}
+
+/// Totals for one network
+#[derive(Default)]
+struct NetworkOutcomes {
+    cyclists: f64,
+    km_cycled_per_year: f64,
+    deaths_prevented_per_year: f64,
+}
+
+impl MapModel {
+    /// Compare cycling uptake on the network without any edits and the current network, and
+    /// estimate the outcomes of the difference. Returns JSON.
+    pub fn estimate_outcomes(
+        &mut self,
+        timer: &mut Timer,
+        opts: &ODOptions,
+        config: &OutcomesConfig,
+    ) -> Result<String> {
+        self.recalculate_router(timer);
+        self.ensure_baseline_router();
+
+        timer.step("route OD trips on both networks");
+        let profile = self.graph.profile_names["bicycle"];
+        let current_router = &self.graph.routers[profile.0];
+        let baseline_router = self.baseline_router.as_ref().unwrap();
+        let zone_weights = self.zone_weights(opts.sampling);
+
+        let mut baseline = NetworkOutcomes::default();
+        let mut current = NetworkOutcomes::default();
+        let mut car_km_displaced_per_year = 0.0;
+        let mut succeeded = 0;
+        let mut failed = 0;
+
+        for (purpose, desire_lines) in &self.desire_lines {
+            let base_model = Scenario::Baseline.model(*purpose);
+            let target_model = opts.scenario.model(*purpose);
+            for desire_line in desire_lines {
+                let current_cycling = desire_line.current_cycling();
+                for (pt1, pt2, count) in
+                    self.sample_trips(*purpose, desire_line, opts, &zone_weights)
+                {
+                    let start = self.graph.snap_to_road(pt1, profile);
+                    let end = self.graph.snap_to_road(pt2, profile);
+                    let (Ok(baseline_route), Ok(current_route)) = (
+                        baseline_router.route(&self.graph, start, end),
+                        current_router.route(&self.graph, start, end),
+                    ) else {
+                        failed += 1;
+                        continue;
+                    };
+                    succeeded += 1;
+
+                    let mut cyclists = [0.0; 2];
+                    for (idx, (route, network, is_baseline)) in [
+                        (&baseline_route, &mut baseline, true),
+                        (&current_route, &mut current, false),
+                    ]
+                    .into_iter()
+                    .enumerate()
+                    {
+                        let (length, gradient) = self.route_length_and_gradient(route);
+                        let base_uptake = base_model.uptake(length, gradient, current_cycling);
+                        let target_uptake = target_model.uptake(length, gradient, current_cycling);
+                        let realised = self.realised_share(route, is_baseline, config);
+                        let uptake = base_uptake + (target_uptake - base_uptake) * realised;
+
+                        let trip_km = length / 1000.0;
+                        cyclists[idx] = uptake * count;
+                        network.cyclists += cyclists[idx];
+                        network.km_cycled_per_year +=
+                            cyclists[idx] * trip_km * config.trips_per_year;
+                        network.deaths_prevented_per_year +=
+                            cyclists[idx] * config.risk_reduction(trip_km) * config.mortality_rate;
+                    }
+
+                    // New cycle trips replace driving the whole way
+                    let car_length = self
+                        .directness_baseline_length(DirectnessBaseline::CarRoute, start, end)
+                        .unwrap_or_else(|| self.snapped_route_length(&current_route));
+                    car_km_displaced_per_year += (cyclists[1] - cyclists[0])
+                        * config.car_mode_shift
+                        * (car_length / 1000.0)
+                        * config.trips_per_year;
+                }
+            }
+        }
+
+        let deaths_prevented_per_year =
+            current.deaths_prevented_per_year - baseline.deaths_prevented_per_year;
+        let out = serde_json::json!({
+            "succeeded": succeeded,
+            "failed": failed,
+            "scenario": opts.scenario,
+            "baseline_cyclists": baseline.cyclists,
+            "current_cyclists": current.cyclists,
+            "additional_cycle_trips_per_year": (current.cyclists - baseline.cyclists) * config.trips_per_year,
+            "additional_km_cycled_per_year": current.km_cycled_per_year - baseline.km_cycled_per_year,
+            "car_km_displaced_per_year": car_km_displaced_per_year,
+            "co2_saved_tonnes_per_year": car_km_displaced_per_year * config.co2_kg_per_car_km / 1000.0,
+            "deaths_prevented_per_year": deaths_prevented_per_year,
+            "health_value_per_year": deaths_prevented_per_year * config.value_of_statistical_life,
+        });
+        Ok(serde_json::to_string(&out)?)
+    }
+
+    /// Weighted by length, how much of the scenario's uptake is realised along a route? If
+    /// `baseline` is true, describe the route as if there were no edits.
+    fn realised_share(&self, route: &Route, baseline: bool, config: &OutcomesConfig) -> f64 {
+        let mut total_length = 0.0;
+        let mut sum = 0.0;
+        for step in &route.steps {
+            if let PathStep::Road { road, .. } = step {
+                let length = self.graph.roads[road.0].length_meters;
+                total_length += length;
+                sum += length * config.realised(self.los_for(*road, baseline));
+            }
+        }
+        if total_length == 0.0 {
+            // A trip within one road; assume that road's level of service
+            if let Some(PathStep::Road { road, .. }) = route.steps.first() {
+                return config.realised(self.los_for(*road, baseline));
+            }
+            return 0.0;
+        }
+        sum / total_length
+    }
+
+    fn los_for(&self, r: RoadID, baseline: bool) -> LevelOfService {
+        if baseline {
+            self.baseline_level_of_service(r)
+        } else {
+            self.los[r.0]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{od::Sampling, tests::unedited_model};
+
+    #[test]
+    fn test_risk_reduction() {
+        let config = OutcomesConfig::default();
+        let mut ok = true;
+        // 100 minutes per week reaches HEAT's reference volume
+        let reference_km = 100.0 * 52.0 / config.trips_per_year / 60.0 * config.cycling_speed_kmh;
+        for (trip_km, expected) in [
+            (0.0, 0.0),
+            (reference_km, 0.1),
+            (2.0 * reference_km, 0.2),
+            // Capped
+            (50.0, 0.45),
+        ] {
+            let actual = config.risk_reduction(trip_km);
+            if (actual - expected).abs() > 1e-9 {
+                println!("For a {trip_km}km trip, expected {expected} but got {actual}");
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+
+    #[test]
+    fn test_unedited_has_no_outcomes() {
+        let mut model = unedited_model();
+        let opts = ODOptions {
+            sampling: Sampling::RoadNetwork,
+            ..Default::default()
+        };
+        let out = model
+            .estimate_outcomes(
+                &mut Timer::new("test outcomes", None),
+                &opts,
+                &OutcomesConfig::default(),
+            )
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_str(&out).unwrap();
+
+        let mut ok = true;
+        if out["succeeded"].as_u64().unwrap() == 0 {
+            println!("No trips were routed");
+            ok = false;
+        }
+        for key in [
+            "additional_cycle_trips_per_year",
+            "additional_km_cycled_per_year",
+            "car_km_displaced_per_year",
+            "co2_saved_tonnes_per_year",
+            "deaths_prevented_per_year",
+            "health_value_per_year",
+        ] {
+            let x = out[key].as_f64().unwrap();
+            if x.abs() > 1e-9 {
+                println!("Without any edits, {key} should be 0, but got {x}");
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+}
@@ -1,14 +1,17 @@
 use std::collections::HashSet;
 
 use anyhow::Result;
-use geo::{BoundingRect, Contains, Intersects, MultiPolygon, Point, Rect};
+use geo::{
+    Area, BooleanOps, BoundingRect, Centroid, Contains, Intersects, LineInterpolatePoint,
+    MultiPolygon, Point, Rect,
+};
 use geojson::Feature;
 use graph::{Graph, RoadID};
 use rstar::AABB;
 use serde::{Deserialize, Serialize};
 use utils::Mercator;
 
-use crate::utils::Quintiles;
+use crate::utils::Quantiles;
 
 // TODO We can't use geojson::ser::to_feature_collection_string and similar magic, because bincode
 // doesn't work with it, and we need to do the CRS transform
@@ -24,12 +27,18 @@ pub struct School {
 }
 
 impl School {
-    pub fn to_gj(&self, mercator: &Mercator, reachable: bool, idx: usize) -> Feature {
+    pub fn to_gj(
+        &self,
+        mercator: &Mercator,
+        connection_distance: Option<f64>,
+        idx: usize,
+    ) -> Feature {
         let mut f = mercator.to_wgs84_gj(&self.point);
         f.set_property("kind", self.kind.clone());
         f.set_property("name", self.name.clone());
         f.set_property("pupils", self.pupils);
-        f.set_property("reachable", reachable);
+        f.set_property("reachable", connection_distance.is_some());
+        f.set_property("connection_distance", connection_distance);
         f.set_property("idx", idx);
         f
     }
@@ -74,11 +83,17 @@ pub struct GPHospital {
 }
 
 impl GPHospital {
-    pub fn to_gj(&self, mercator: &Mercator, reachable: bool, idx: usize) -> Feature {
+    pub fn to_gj(
+        &self,
+        mercator: &Mercator,
+        connection_distance: Option<f64>,
+        idx: usize,
+    ) -> Feature {
         let mut f = mercator.to_wgs84_gj(&self.point);
         f.set_property("kind", self.kind.clone());
         f.set_property("name", self.name.clone());
-        f.set_property("reachable", reachable);
+        f.set_property("reachable", connection_distance.is_some());
+        f.set_property("connection_distance", connection_distance);
         f.set_property("idx", idx);
         f
     }
@@ -126,10 +141,16 @@ pub struct TownCentre {
 }
 
 impl TownCentre {
-    pub fn to_gj(&self, mercator: &Mercator, reachable: bool, idx: usize) -> Feature {
+    pub fn to_gj(
+        &self,
+        mercator: &Mercator,
+        connection_distance: Option<f64>,
+        idx: usize,
+    ) -> Feature {
         let mut f = mercator.to_wgs84_gj(&self.polygon);
         f.set_property("name", self.name.clone());
-        f.set_property("reachable", reachable);
+        f.set_property("reachable", connection_distance.is_some());
+        f.set_property("connection_distance", connection_distance);
         f.set_property("idx", idx);
         f
     }
@@ -170,19 +191,35 @@ struct TownCentreGJ {
 
 #[derive(Serialize, Deserialize)]
 pub struct DataZone {
-    polygon: MultiPolygon,
+    pub polygon: MultiPolygon,
     id: String,
     imd_rank: usize,
     pub imd_percentile: usize,
     pub population: usize,
     pub roads: HashSet<RoadID>,
     area_km2: f64,
-    // Relative to the study area, not all of Scotland
-    density_quintile: usize,
+    // Relative to the study area, not all of Scotland. Quintiles are weighted by population.
+    pub density_quintile: usize,
+}
+
+/// From 0 for the most deprived quintile to 4 for the least, given a percentile in [1, 100]
+pub(crate) fn imd_quintile_index(imd_percentile: usize) -> usize {
+    (imd_percentile.saturating_sub(1) / 20).min(4)
 }
 
 impl DataZone {
-    pub fn to_gj(&self, mercator: &Mercator, reachable: bool) -> Feature {
+    /// From 0 for the most deprived quintile to 4 for the least
+    pub fn imd_quintile_index(&self) -> usize {
+        imd_quintile_index(self.imd_percentile)
+    }
+
+    /// From 0 for the densest quintile to 4 for the least dense. `density_quintile` comes from
+    /// `Quantiles::bin`, so it's always [1, 5].
+    pub fn density_quintile_index(&self) -> usize {
+        self.density_quintile - 1
+    }
+
+    pub fn to_gj(&self, mercator: &Mercator, connection_distance: Option<f64>) -> Feature {
         let mut f = mercator.to_wgs84_gj(&self.polygon);
         f.set_property("id", self.id.clone());
         f.set_property("imd_rank", self.imd_rank);
@@ -190,7 +227,8 @@ impl DataZone {
         f.set_property("population", self.population);
         f.set_property("area_km2", self.area_km2);
         f.set_property("density_quintile", self.density_quintile);
-        f.set_property("reachable", reachable);
+        f.set_property("reachable", connection_distance.is_some());
+        f.set_property("connection_distance", connection_distance);
         f
     }
 
@@ -229,14 +267,14 @@ impl DataZone {
                     density_quintile: 0,
                 });
 
-                densities.push(((x.population as f64) / area_km2) as usize);
+                densities.push((((x.population as f64) / area_km2) as usize, x.population));
             }
         }
 
-        let stats = Quintiles::new(&densities);
+        // Weighted by population, so each quintile has about a fifth of the people
+        let stats = Quantiles::weighted(5, densities);
         for zone in &mut zones {
-            zone.density_quintile =
-                stats.quintile(((zone.population as f64) / zone.area_km2) as usize);
+            zone.density_quintile = stats.bin(((zone.population as f64) / zone.area_km2) as usize);
         }
 
         info!("Matched {} data zones", zones.len());
@@ -255,3 +293,77 @@ struct DataZoneGJ {
     population: usize,
     area: f64,
 }
+
+/// A council (LAD) or region inside the study area, so stats can be broken down per area
+#[derive(Serialize, Deserialize)]
+pub struct SubArea {
+    pub name: String,
+    /// LAD or REGION
+    pub kind: String,
+    pub polygon: MultiPolygon,
+    /// Roads with their middle inside the polygon
+    pub roads: HashSet<RoadID>,
+}
+
+impl SubArea {
+    /// Only keeps areas mostly inside the boundary, skipping any that cover the whole boundary
+    /// (like the region containing one LAD), since stats for those repeat the overall stats.
+    pub fn from_gj(gj: &str, boundary_wgs84: &MultiPolygon, graph: &Graph) -> Result<Vec<Self>> {
+        let boundary_area = boundary_wgs84.unsigned_area();
+
+        let mut areas = Vec::new();
+        for x in geojson::de::deserialize_feature_collection_str_to_vec::<SubAreaGJ>(gj)? {
+            if !boundary_wgs84.intersects(&x.geometry) {
+                continue;
+            }
+            // The boundaries are simplified, so neighbours overlap a bit
+            let inside = boundary_wgs84.intersection(&x.geometry).unsigned_area();
+            if inside < 0.5 * x.geometry.unsigned_area() || inside > 0.99 * boundary_area {
+                continue;
+            }
+
+            let polygon = graph.mercator.to_mercator(&x.geometry);
+            let bbox = polygon.bounding_rect().unwrap();
+            let roads = graph
+                .roads
+                .iter()
+                .enumerate()
+                .filter(|(_, road)| {
+                    road.linestring
+                        .line_interpolate_point(0.5)
+                        .is_some_and(|pt| bbox.intersects(&pt) && polygon.contains(&pt))
+                })
+                .map(|(idx, _)| RoadID(idx))
+                .collect();
+
+            areas.push(SubArea {
+                name: x.name,
+                kind: x.kind,
+                polygon,
+                roads,
+            });
+        }
+
+        info!("Matched {} sub-areas", areas.len());
+        Ok(areas)
+    }
+
+    pub fn contains_road(&self, r: RoadID) -> bool {
+        self.roads.contains(&r)
+    }
+
+    /// Data zones belong to the area containing their centroid
+    pub fn contains_data_zone(&self, zone: &DataZone) -> bool {
+        zone.polygon
+            .centroid()
+            .is_some_and(|pt| self.polygon.contains(&pt))
+    }
+}
+
+#[derive(Deserialize)]
+struct SubAreaGJ {
+    #[serde(deserialize_with = "geojson::de::deserialize_geometry")]
+    geometry: MultiPolygon,
+    name: String,
+    kind: String,
+}
@@ -1,12 +1,11 @@
 use anyhow::Result;
 use geojson::FeatureCollection;
 
-use crate::{utils::Quintiles, MapModel};
+use crate::MapModel;
 
 impl MapModel {
-    pub fn render_precalculated_flows(&self) -> Result<String> {
-        // TODO Could cache
-        let stats = Quintiles::new(&self.precalculated_flows);
+    pub fn render_precalculated_flows(&mut self) -> Result<String> {
+        let stats = self.flow_quintiles();
         let mut covered_quintile_sums = [0; 5];
 
         let mut features = Vec::new();
@@ -24,7 +23,7 @@ impl MapModel {
             let covered = self.infra_types[idx].is_some();
             f.set_property("covered", covered);
 
-            let quintile = stats.quintile(*flow);
+            let quintile = stats.bin(*flow);
             f.set_property("quintile", quintile);
 
             if covered {
@@ -39,7 +38,8 @@ impl MapModel {
             bbox: None,
             foreign_members: Some(
                 serde_json::json!({
-                    "total_quintile_sums": stats.total_quintile_sums,
+                    "total_quintile_sums": stats.sums,
+                    "quintile_labels": stats.labels(),
                     "covered_quintile_sums": covered_quintile_sums,
                 })
                 .as_object()
@@ -3,6 +3,7 @@ use std::collections::{BinaryHeap, HashMap, HashSet};
 use anyhow::Result;
 use geojson::FeatureCollection;
 use graph::RoadID;
+use serde::{Deserialize, Serialize};
 use utils::PriorityQueueItem;
 
 use crate::{InfraType, LevelOfService, MapModel};
@@ -11,6 +12,21 @@ pub struct Reachability {
     pub network: HashSet<RoadID>,
     pub severances: HashSet<RoadID>,
     pub reachable: HashSet<RoadID>,
+    /// For every reachable road, the distance in meters along other roads from the network to
+    /// the nearest end of this road. Roads on the network aren't included.
+    pub connection_distances: HashMap<RoadID, f64>,
+}
+
+/// Limits on how far a destination can be from the network and still count as reachable. By
+/// default, there's no limit.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReachabilityOptions {
+    /// The distance in meters along roads off the network, before reaching the destination's road
+    pub max_connection_meters: Option<f64>,
+    /// The distance in meters from the network to the far end of the destination's road, so the
+    /// whole trip to anywhere along it is limited
+    pub max_trip_meters: Option<f64>,
 }
 
 impl Reachability {
@@ -21,16 +37,41 @@ impl Reachability {
     pub fn covers_any(&self, roads: &HashSet<RoadID>) -> bool {
         !self.network.is_disjoint(roads) || !self.reachable.is_disjoint(roads)
     }
+
+    /// How far is a road from the network? Zero if it's part of the network, and None if it's
+    /// not reachable.
+    pub fn connection_distance(&self, r: RoadID) -> Option<f64> {
+        if self.network.contains(&r) {
+            return Some(0.0);
+        }
+        self.connection_distances.get(&r).cloned()
+    }
+
+    /// The smallest connection distance of any of the roads
+    pub fn connection_distance_any(&self, roads: &HashSet<RoadID>) -> Option<f64> {
+        roads
+            .iter()
+            .filter_map(|r| self.connection_distance(*r))
+            .min_by(|a, b| a.total_cmp(b))
+    }
 }
 
 impl MapModel {
     pub fn get_reachable_network(&self) -> Reachability {
+        self.get_reachable_network_with(&ReachabilityOptions::default())
+    }
+
+    /// Flood from the network through roads with a high level of service, by distance, stopping
+    /// at the limits
+    pub fn get_reachable_network_with(&self, opts: &ReachabilityOptions) -> Reachability {
         let mut network: HashSet<RoadID> = HashSet::new();
         let mut severances: HashSet<RoadID> = HashSet::new();
         let mut reachable: HashSet<RoadID> = HashSet::new();
+        let mut connection_distances: HashMap<RoadID, f64> = HashMap::new();
 
         let mut visited: HashSet<RoadID> = HashSet::new();
-        let mut queue: Vec<RoadID> = Vec::new();
+        // Costs are in centimeters from the network
+        let mut queue: BinaryHeap<PriorityQueueItem<usize, RoadID>> = BinaryHeap::new();
 
         for idx in 0..self.graph.roads.len() {
             let id = RoadID(idx);
@@ -39,7 +80,7 @@ impl MapModel {
                 // service is poor, consider it part of the network or not?
                 if infra_type != InfraType::MixedTraffic {
                     network.insert(id);
-                    queue.push(id);
+                    queue.push(PriorityQueueItem::new(0, id));
                     continue;
                 }
             }
@@ -50,19 +91,46 @@ impl MapModel {
         }
 
         // Flood, avoiding severances
-        while let Some(r) = queue.pop() {
-            // TODO Simplify and just put these in visited upfront?
+        while let Some(item) = queue.pop() {
+            let r = item.value;
             if visited.contains(&r) || severances.contains(&r) {
                 continue;
             }
-            visited.insert(r);
 
             let road = &self.graph.roads[r.0];
-            if !network.contains(&r) {
+            let on_network = network.contains(&r);
+            let distance = (item.cost as f64) / 100.0;
+            if !on_network {
+                // Everything left in the queue is at least as far, but might be a shorter road,
+                // so only skip this one
+                if opts
+                    .max_trip_meters
+                    .is_some_and(|max| distance + road.length_meters > max)
+                {
+                    continue;
+                }
+                if opts.max_connection_meters.is_some_and(|max| distance > max) {
+                    break;
+                }
+            }
+            visited.insert(r);
+
+            if !on_network {
                 reachable.insert(r);
+                connection_distances.insert(r, distance);
             }
+            // Moving along the network itself doesn't count towards the connection
+            let next_cost = if on_network {
+                item.cost
+            } else {
+                item.cost + meters(road.length_meters)
+            };
             for i in [road.src_i, road.dst_i] {
-                queue.extend(self.graph.intersections[i.0].roads.clone());
+                for r2 in &self.graph.intersections[i.0].roads {
+                    if !visited.contains(r2) {
+                        queue.push(PriorityQueueItem::new(next_cost, *r2));
+                    }
+                }
             }
         }
 
@@ -70,17 +138,18 @@ impl MapModel {
             network,
             severances,
             reachable,
+            connection_distances,
         }
     }
 
-    pub fn render_reachable_network(&self) -> Result<String> {
+    pub fn render_reachable_network(&mut self) -> Result<String> {
         let mut features = Vec::new();
-        let out = self.get_reachable_network();
+        let out = self.reachable_network(&ReachabilityOptions::default());
 
         for (kind, list) in [
-            ("network", out.network),
-            ("severance", out.severances),
-            ("reachable", out.reachable),
+            ("network", &out.network),
+            ("severance", &out.severances),
+            ("reachable", &out.reachable),
         ] {
             for r in list {
                 let mut f = self
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use geo::LineString;
+use geojson::FeatureCollection;
+use graph::Timer;
+
+use crate::{
+    join_lines::KeyedLineString,
+    od::{ODOptions, TripPurpose},
+    uptake::Scenario,
+    Dir, MapModel,
+};
+
+/// OD counts assigned to the network, in the same shape as NPT's route network (rnet), so the two
+/// can be compared
+pub struct RouteNetwork {
+    /// Named like NPT's columns, starting with all purposes combined
+    pub columns: Vec<String>,
+    /// Linestrings in WGS84, with one count per column
+    pub lines: Vec<(LineString, Vec<usize>)>,
+}
+
+impl MapModel {
+    /// Route all desire lines, then join adjacent roads with the same counts. Roads without any
+    /// trips are skipped.
+    pub fn od_route_network(
+        &mut self,
+        timer: &mut Timer,
+        opts: &ODOptions,
+    ) -> Result<RouteNetwork> {
+        let out = self.cached_od_counts(timer, opts)?;
+
+        let mut columns = vec![npt_column("all", opts.scenario)];
+        for purpose in out.per_purpose.keys() {
+            columns.push(npt_column(purpose_prefix(*purpose), opts.scenario));
+        }
+
+        timer.step("join roads with the same counts");
+        // Linestrings can only be joined if every count matches. Give each distinct set of counts
+        // a key.
+        let mut keys: HashMap<Vec<usize>, usize> = HashMap::new();
+        let mut values = Vec::new();
+        let mut pieces = Vec::new();
+        for (r, count) in &out.counts {
+            if *count == 0 {
+                continue;
+            }
+            let mut row = vec![*count];
+            for purpose_out in out.per_purpose.values() {
+                row.push(purpose_out.counts.get(r).cloned().unwrap_or(0));
+            }
+            let key = *keys.entry(row.clone()).or_insert_with(|| {
+                values.push(row);
+                values.len() - 1
+            });
+            pieces.push(KeyedLineString {
+                linestring: self.graph.roads[r.0].linestring.clone(),
+                ids: vec![(*r, Dir::Forwards)],
+                key,
+            });
+        }
+        // Make the output independent of HashMap order
+        pieces.sort_by_key(|line| line.ids[0].0 .0);
+
+        let lines = crate::join_lines::collapse_degree_2(pieces)
+            .into_iter()
+            .map(|line| {
+                let mut linestring = line.linestring;
+                self.graph.mercator.to_wgs84_in_place(&mut linestring);
+                (linestring, values[line.key].clone())
+            })
+            .collect();
+
+        Ok(RouteNetwork { columns, lines })
+    }
+}
+
+impl RouteNetwork {
+    pub fn to_geojson(&self) -> Result<String> {
+        let mut features = Vec::new();
+        for (linestring, counts) in &self.lines {
+            let mut f = geojson::Feature::from(geojson::Geometry::from(linestring));
+            for (column, count) in self.columns.iter().zip(counts) {
+                f.set_property(column.clone(), *count);
+            }
+            features.push(f);
+        }
+        Ok(serde_json::to_string(&FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: None,
+        })?)
+    }
+}
+
+/// Matches NPT's `{purpose}_fastest_{scenario}` columns, like `all_fastest_bicycle_go_dutch`
+fn npt_column(prefix: &str, scenario: Scenario) -> String {
+    let suffix = match scenario {
+        Scenario::Baseline => "bicycle",
+        Scenario::GoDutch => "bicycle_go_dutch",
+        Scenario::Ebike => "bicycle_ebike",
+        Scenario::GovTarget => "bicycle_govtarget",
+    };
+    format!("{prefix}_fastest_{suffix}")
+}
+
+fn purpose_prefix(purpose: TripPurpose) -> &'static str {
+    match purpose {
+        TripPurpose::Commute => "commute",
+        TripPurpose::School => "school",
+        TripPurpose::Utility => "utility",
+        TripPurpose::Leisure => "leisure",
+    }
+}
@@ -279,7 +279,7 @@ impl MapModel {
     // TODO Use CbD guidance. Simple for now
     // This assumes this road doesn't have anything set yet, and so its LoS isn't based on an
     // InfraType already
-    fn best_infra_type(&self, r: RoadID) -> InfraType {
+    pub(crate) fn best_infra_type(&self, r: RoadID) -> InfraType {
         match self.los[r.0] {
             // Already fine, just indicate it's a route
             LevelOfService::High => InfraType::MixedTraffic,
@@ -292,7 +292,7 @@ impl MapModel {
 }
 
 // Mimic enough of what the route snapper creates, so the segment can be edited in the web app
-fn make_route_snapper_feature(
+pub(crate) fn make_route_snapper_feature(
     graph: &Graph,
     ids: &[(RoadID, Dir)],
     linestring: &LineString,
@@ -361,7 +361,7 @@ fn trim_lon_lat(x: f64) -> f64 {
 }
 
 // TODO Upstream to graph
-fn glue_route(graph: &Graph, roads: &[(RoadID, Dir)]) -> LineString {
+pub(crate) fn glue_route(graph: &Graph, roads: &[(RoadID, Dir)]) -> LineString {
     graph::Route {
         start: start_pos(roads[0], graph),
         end: end_pos(*roads.last().unwrap(), graph),
@@ -0,0 +1,365 @@
+use std::collections::{BinaryHeap, HashSet};
+
+use anyhow::Result;
+use enum_map::Enum;
+use geo::{Coord, Distance, Euclidean, Point};
+use geojson::FeatureCollection;
+use graph::{IntersectionID, RoadID};
+use rstar::{primitives::GeomWithData, PointDistance, RTree};
+use serde::Serialize;
+
+use utils::PriorityQueueItem;
+
+use crate::{reachable::ReachabilityOptions, LevelOfService, MapModel};
+
+// Above this speed, a road is hard to cross
+const HIGH_SPEED_MPH: usize = 30;
+// At or above this many vehicles per day, a road is hard to cross
+const HIGH_TRAFFIC: usize = 4000;
+// How far across a severance road to look for the far side, in meters
+const FAR_SIDE_SEARCH_METERS: f64 = 100.0;
+// Stop measuring a detour to the far side after this many extra meters
+const MAX_DETOUR_METERS: f64 = 2000.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum SeveranceClass {
+    LowSpeedLowTraffic,
+    LowSpeedHighTraffic,
+    HighSpeedLowTraffic,
+    HighSpeedHighTraffic,
+}
+
+impl SeveranceClass {
+    fn new(speed_mph: usize, traffic: usize) -> Self {
+        match (speed_mph > HIGH_SPEED_MPH, traffic >= HIGH_TRAFFIC) {
+            (false, false) => Self::LowSpeedLowTraffic,
+            (false, true) => Self::LowSpeedHighTraffic,
+            (true, false) => Self::HighSpeedLowTraffic,
+            (true, true) => Self::HighSpeedHighTraffic,
+        }
+    }
+}
+
+/// A place where the network or the area reachable from it meets a severance
+pub struct SeveranceCrossing {
+    pub intersection: IntersectionID,
+    /// WGS84
+    pub point: Point,
+    /// "network" if a route with infrastructure touches it, otherwise "reachable"
+    pub kind: &'static str,
+    /// How many severance roads meet here
+    pub num_severances: usize,
+    /// Describing the fastest, busiest severance road here
+    pub name: Option<String>,
+    pub speed_mph: usize,
+    pub traffic: usize,
+    pub los: LevelOfService,
+    pub class: SeveranceClass,
+    /// The population of places not reachable yet, but directly across the severance roads
+    pub far_side_population: usize,
+    /// How much further than a straight line it is to reach the nearest intersection directly
+    /// across, using any road. `None` if there's nothing across or no way around within
+    /// `MAX_DETOUR_METERS`.
+    pub far_side_detour_meters: Option<f64>,
+}
+
+/// One crossing as flat columns, for CSV or GeoJSON output
+#[derive(Default, Serialize)]
+pub struct SeveranceCrossingRecord {
+    intersection: usize,
+    lon: f64,
+    lat: f64,
+    kind: String,
+    num_severances: usize,
+    name: Option<String>,
+    speed_mph: usize,
+    traffic: usize,
+    los: String,
+    class: String,
+    far_side_population: usize,
+    far_side_detour_meters: Option<f64>,
+}
+
+impl SeveranceCrossing {
+    pub fn to_record(&self) -> SeveranceCrossingRecord {
+        SeveranceCrossingRecord {
+            intersection: self.intersection.0,
+            lon: self.point.x(),
+            lat: self.point.y(),
+            kind: self.kind.to_string(),
+            num_severances: self.num_severances,
+            name: self.name.clone(),
+            speed_mph: self.speed_mph,
+            traffic: self.traffic,
+            los: format!("{:?}", self.los),
+            class: format!("{:?}", self.class),
+            far_side_population: self.far_side_population,
+            far_side_detour_meters: self.far_side_detour_meters,
+        }
+    }
+}
+
+impl MapModel {
+    /// Find every intersection where a severance meets the network or the reachable area, with
+    /// the crossings that'd reach the most people first
+    pub fn severance_crossings(&mut self) -> Vec<SeveranceCrossing> {
+        let roads = self.reachable_network(&ReachabilityOptions::default());
+
+        // Group the quiet roads that can't be reached yet into connected areas
+        let unreached = |r: RoadID| !roads.covers(r) && !roads.severances.contains(&r);
+        let mut areas: Vec<Option<usize>> = vec![None; self.graph.intersections.len()];
+        let mut num_areas = 0;
+        for (idx, road) in self.graph.roads.iter().enumerate() {
+            if !unreached(RoadID(idx)) || areas[road.src_i.0].is_some() {
+                continue;
+            }
+            let area = num_areas;
+            num_areas += 1;
+            let mut queue = vec![road.src_i, road.dst_i];
+            while let Some(i) = queue.pop() {
+                if areas[i.0].is_some() {
+                    continue;
+                }
+                areas[i.0] = Some(area);
+                for r in &self.graph.intersections[i.0].roads {
+                    if unreached(*r) {
+                        let road = &self.graph.roads[r.0];
+                        queue.push(road.src_i);
+                        queue.push(road.dst_i);
+                    }
+                }
+            }
+        }
+
+        let unreached_nodes = RTree::bulk_load(
+            self.graph
+                .intersections
+                .iter()
+                .filter(|i| areas[i.id.0].is_some())
+                .map(|i| GeomWithData::new([i.point.x(), i.point.y()], i.id))
+                .collect(),
+        );
+
+        let mut area_population = vec![0; num_areas];
+        for zone in &self.data_zones {
+            if roads.covers_any(&zone.roads) {
+                continue;
+            }
+            let touching: HashSet<usize> = zone
+                .roads
+                .iter()
+                .filter(|r| unreached(**r))
+                .filter_map(|r| areas[self.graph.roads[r.0].src_i.0])
+                .collect();
+            for area in touching {
+                area_population[area] += zone.population;
+            }
+        }
+
+        let mut crossings = Vec::new();
+        for intersection in &self.graph.intersections {
+            let mut on_network = false;
+            let mut covered = false;
+            let mut severances = Vec::new();
+            for r in &intersection.roads {
+                if roads.network.contains(r) {
+                    on_network = true;
+                }
+                if roads.covers(*r) {
+                    covered = true;
+                } else if roads.severances.contains(r) {
+                    severances.push(*r);
+                }
+            }
+            if !covered || severances.is_empty() {
+                continue;
+            }
+
+            // What's directly across each severance? The other end of the severance road is
+            // usually on the same side, further along it.
+            let near: Vec<RoadID> = intersection
+                .roads
+                .iter()
+                .filter(|r| roads.covers(**r))
+                .cloned()
+                .collect();
+            let mut far_sides: Vec<IntersectionID> = severances
+                .iter()
+                .filter_map(|r| self.node_across(intersection.id, *r, &near, &unreached_nodes))
+                .collect();
+            far_sides.sort_by(|a, b| {
+                let distance = |i: &IntersectionID| {
+                    Euclidean::distance(intersection.point, self.graph.intersections[i.0].point)
+                };
+                distance(a).total_cmp(&distance(b))
+            });
+            let far_areas: HashSet<usize> = far_sides.iter().filter_map(|i| areas[i.0]).collect();
+            let far_side_detour_meters = far_sides
+                .first()
+                .and_then(|far| self.detour_meters(intersection.id, *far));
+
+            let worst = *severances
+                .iter()
+                .max_by_key(|r| (self.speeds[r.0], self.traffic_volumes[r.0]))
+                .unwrap();
+            crossings.push(SeveranceCrossing {
+                intersection: intersection.id,
+                point: self.graph.mercator.to_wgs84(&intersection.point),
+                kind: if on_network { "network" } else { "reachable" },
+                num_severances: severances.len(),
+                name: self.graph.roads[worst.0].osm_tags.get("name").cloned(),
+                speed_mph: self.speeds[worst.0],
+                traffic: self.traffic_volumes[worst.0],
+                los: self.los[worst.0],
+                class: SeveranceClass::new(self.speeds[worst.0], self.traffic_volumes[worst.0]),
+                far_side_population: far_areas.into_iter().map(|a| area_population[a]).sum(),
+                far_side_detour_meters,
+            });
+        }
+
+        crossings.sort_by_key(|x| {
+            (
+                std::cmp::Reverse(x.far_side_population),
+                std::cmp::Reverse(x.los.into_usize()),
+                x.intersection,
+            )
+        });
+        crossings
+    }
+
+    /// Find the nearest intersection in `candidates` directly across a severance road from `i`,
+    /// on the opposite side to the `near` roads. If the near roads are on both sides, there's no
+    /// far side.
+    fn node_across(
+        &self,
+        i: IntersectionID,
+        severance: RoadID,
+        near: &[RoadID],
+        candidates: &RTree<GeomWithData<[f64; 2], IntersectionID>>,
+    ) -> Option<IntersectionID> {
+        let pt = self.graph.intersections[i.0].point.into();
+        let along = self.next_point(severance, i);
+
+        let mut near_sides = near
+            .iter()
+            .map(|r| side_of_line(pt, along, self.next_point(*r, i)) > 0.0);
+        let near_is_left = near_sides.next()?;
+        if near_sides.any(|x| x != near_is_left) {
+            return None;
+        }
+
+        candidates
+            .locate_within_distance([pt.x, pt.y], FAR_SIDE_SEARCH_METERS.powi(2))
+            .filter(|x| {
+                let [x, y] = *x.geom();
+                let side = side_of_line(pt, along, Coord { x, y });
+                side != 0.0 && (side > 0.0) != near_is_left
+            })
+            .min_by(|a, b| {
+                a.distance_2(&[pt.x, pt.y])
+                    .total_cmp(&b.distance_2(&[pt.x, pt.y]))
+            })
+            .map(|x| x.data)
+    }
+
+    /// The first point along a road, starting from one of its intersections
+    fn next_point(&self, r: RoadID, i: IntersectionID) -> Coord {
+        let road = &self.graph.roads[r.0];
+        let pts = &road.linestring.0;
+        if road.src_i == i {
+            pts[1]
+        } else {
+            pts[pts.len() - 2]
+        }
+    }
+
+    /// How much further than a straight line is it between two intersections, along any road in
+    /// either direction? `None` if it's more than `MAX_DETOUR_METERS` further.
+    fn detour_meters(&self, from: IntersectionID, to: IntersectionID) -> Option<f64> {
+        let straight_line = Euclidean::distance(
+            self.graph.intersections[from.0].point,
+            self.graph.intersections[to.0].point,
+        );
+        let max_distance = straight_line + MAX_DETOUR_METERS;
+
+        // Costs are in centimeters, since PriorityQueueItem needs Ord
+        let mut queue: BinaryHeap<PriorityQueueItem<usize, IntersectionID>> = BinaryHeap::new();
+        queue.push(PriorityQueueItem::new(0, from));
+        let mut visited: HashSet<IntersectionID> = HashSet::new();
+        while let Some(item) = queue.pop() {
+            let i = item.value;
+            if i == to {
+                return Some((item.cost as f64) / 100.0 - straight_line);
+            }
+            if !visited.insert(i) {
+                continue;
+            }
+
+            for r in &self.graph.intersections[i.0].roads {
+                let road = &self.graph.roads[r.0];
+                let next = if road.src_i == i {
+                    road.dst_i
+                } else {
+                    road.src_i
+                };
+                let cost = item.cost + ((road.length_meters * 100.0).round() as usize);
+                if !visited.contains(&next) && (cost as f64) / 100.0 <= max_distance {
+                    queue.push(PriorityQueueItem::new(cost, next));
+                }
+            }
+        }
+        None
+    }
+
+    pub fn severance_crossings_to_geojson(
+        &self,
+        crossings: &[SeveranceCrossing],
+    ) -> Result<String> {
+        let mut features = Vec::new();
+        for crossing in crossings {
+            let mut f = geojson::Feature::from(geojson::Geometry::from(&crossing.point));
+            if let serde_json::Value::Object(props) = serde_json::to_value(crossing.to_record())? {
+                f.properties = Some(props);
+            }
+            features.push(f);
+        }
+        Ok(serde_json::to_string(&FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: None,
+        })?)
+    }
+}
+
+/// Positive if `pt` is left of the line from `from` through `to`, negative if it's right, and 0 if
+/// it's on the line
+fn side_of_line(from: Coord, to: Coord, pt: Coord) -> f64 {
+    (to.x - from.x) * (pt.y - from.y) - (to.y - from.y) * (pt.x - from.x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let mut ok = true;
+        for (speed_mph, traffic, expected) in [
+            (20, 500, SeveranceClass::LowSpeedLowTraffic),
+            (30, 3999, SeveranceClass::LowSpeedLowTraffic),
+            (30, 4000, SeveranceClass::LowSpeedHighTraffic),
+            (40, 1000, SeveranceClass::HighSpeedLowTraffic),
+            (60, 20000, SeveranceClass::HighSpeedHighTraffic),
+        ] {
+            let actual = SeveranceClass::new(speed_mph, traffic);
+            if actual != expected {
+                println!("For {speed_mph} mph and {traffic} vehicles, expected {expected:?} but got {actual:?}");
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::Result;
+use geojson::FeatureCollection;
+use graph::{RoadID, Timer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{stats::StatsOptions, MapModel, Route};
+
+/// The network at one point in time, along with the stats calculated for it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    routes: HashMap<usize, Route>,
+    id_counter: usize,
+    opts: StatsOptions,
+    /// The output of recalculate_stats
+    stats: Value,
+    /// Only roads with some trips, sorted
+    od_counts: Vec<(RoadID, usize)>,
+}
+
+impl MapModel {
+    /// Calculate stats for the current network and remember them under a name, replacing any
+    /// previous snapshot with the same name. Returns the stats as JSON.
+    pub fn take_snapshot(
+        &mut self,
+        timer: &mut Timer,
+        name: String,
+        opts: &StatsOptions,
+    ) -> Result<String> {
+        let (stats, od) = self.calculate_stats(timer, opts)?;
+        let mut od_counts: Vec<(RoadID, usize)> = od
+            .counts
+            .iter()
+            .filter(|(_, count)| **count > 0)
+            .map(|(r, count)| (*r, *count))
+            .collect();
+        od_counts.sort();
+
+        let stats = Value::Object(stats);
+        let out = serde_json::to_string(&stats)?;
+        self.snapshots.insert(
+            name,
+            Snapshot {
+                routes: self.routes.clone(),
+                id_counter: self.id_counter,
+                opts: opts.clone(),
+                stats,
+                od_counts,
+            },
+        );
+        Ok(out)
+    }
+
+    /// Returns the names of every snapshot and the stats calculated for it, as JSON
+    pub fn list_snapshots(&self) -> Result<String> {
+        let out: serde_json::Map<String, Value> = self
+            .snapshots
+            .iter()
+            .map(|(name, snapshot)| (name.clone(), snapshot.stats.clone()))
+            .collect();
+        Ok(serde_json::to_string(&out)?)
+    }
+
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<()> {
+        if self.snapshots.remove(name).is_none() {
+            bail!("no snapshot called {name}");
+        }
+        Ok(())
+    }
+
+    /// Replace all routes with the ones from a snapshot
+    pub fn restore_snapshot(&mut self, name: &str) -> Result<()> {
+        let Some(snapshot) = self.snapshots.get(name) else {
+            bail!("no snapshot called {name}");
+        };
+        self.routes = snapshot.routes.clone();
+        self.id_counter = snapshot.id_counter;
+        self.recalculate_after_edits();
+        Ok(())
+    }
+
+    /// Compare two snapshots. Returns GeoJSON with every road whose OD count changed, and a
+    /// foreign member with the before, after, and change of every stat. Both snapshots must use
+    /// the same options, or the differences wouldn't only come from the edits.
+    pub fn diff_snapshots(&self, before_name: &str, after_name: &str) -> Result<String> {
+        let Some(before) = self.snapshots.get(before_name) else {
+            bail!("no snapshot called {before_name}");
+        };
+        let Some(after) = self.snapshots.get(after_name) else {
+            bail!("no snapshot called {after_name}");
+        };
+        if before.opts != after.opts {
+            bail!("{before_name} and {after_name} were calculated with different options, so they can't be compared. Take both snapshots again with the same options.");
+        }
+
+        let before_counts: BTreeMap<RoadID, usize> = before.od_counts.iter().cloned().collect();
+        let after_counts: BTreeMap<RoadID, usize> = after.od_counts.iter().cloned().collect();
+        let roads: BTreeSet<RoadID> = before_counts
+            .keys()
+            .chain(after_counts.keys())
+            .cloned()
+            .collect();
+
+        let mut features = Vec::new();
+        let mut max_change = 0;
+        for r in roads {
+            let count1 = before_counts.get(&r).cloned().unwrap_or(0);
+            let count2 = after_counts.get(&r).cloned().unwrap_or(0);
+            if count1 == count2 {
+                continue;
+            }
+            let change = (count2 as isize) - (count1 as isize);
+            max_change = max_change.max(change.unsigned_abs());
+
+            let mut f = self
+                .graph
+                .mercator
+                .to_wgs84_gj(&self.graph.roads[r.0].linestring);
+            f.set_property("road", r.0);
+            f.set_property("before", count1);
+            f.set_property("after", count2);
+            f.set_property("change", change);
+            features.push(f);
+        }
+
+        let mut foreign_members = serde_json::Map::new();
+        foreign_members.insert("max_change".to_string(), max_change.into());
+        foreign_members.insert("stats".to_string(), diff_json(&before.stats, &after.stats));
+        Ok(serde_json::to_string(&FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: Some(foreign_members),
+        })?)
+    }
+}
+
+/// Compare two JSON values with the same shape. Every number becomes an object with `before`,
+/// `after`, and `change`. Objects and equal-length arrays are compared by each key or element.
+/// Anything else, like strings or keys only present on one side, just has `before` and `after`.
+fn diff_json(before: &Value, after: &Value) -> Value {
+    match (before, after) {
+        (Value::Number(x1), Value::Number(x2)) => {
+            let x1 = x1.as_f64().unwrap_or(0.0);
+            let x2 = x2.as_f64().unwrap_or(0.0);
+            serde_json::json!({
+                "before": x1,
+                "after": x2,
+                "change": x2 - x1,
+            })
+        }
+        (Value::Object(map1), Value::Object(map2)) => {
+            let keys: BTreeSet<&String> = map1.keys().chain(map2.keys()).collect();
+            Value::Object(
+                keys.into_iter()
+                    .map(|key| {
+                        let diff = diff_json(
+                            map1.get(key).unwrap_or(&Value::Null),
+                            map2.get(key).unwrap_or(&Value::Null),
+                        );
+                        (key.clone(), diff)
+                    })
+                    .collect(),
+            )
+        }
+        (Value::Array(list1), Value::Array(list2)) if list1.len() == list2.len() => Value::Array(
+            list1
+                .iter()
+                .zip(list2)
+                .map(|(x1, x2)| diff_json(x1, x2))
+                .collect(),
+        ),
+        _ => serde_json::json!({
+            "before": before,
+            "after": after,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{od::Sampling, tests::unedited_model};
+    use serde_json::json;
+
+    #[test]
+    fn test_diff_json() {
+        let mut ok = true;
+        for (before, after, expected) in [
+            (
+                json!(0.5),
+                json!(0.75),
+                json!({"before": 0.5, "after": 0.75, "change": 0.25}),
+            ),
+            (
+                json!({"x": 1, "y": "GoDutch"}),
+                json!({"x": 3, "y": "Ebike", "z": 2}),
+                json!({
+                    "x": {"before": 1.0, "after": 3.0, "change": 2.0},
+                    "y": {"before": "GoDutch", "after": "Ebike"},
+                    "z": {"before": null, "after": 2},
+                }),
+            ),
+            (
+                json!([1, 2]),
+                json!([1, 5]),
+                json!([
+                    {"before": 1.0, "after": 1.0, "change": 0.0},
+                    {"before": 2.0, "after": 5.0, "change": 3.0},
+                ]),
+            ),
+            (
+                json!([1]),
+                json!([1, 2]),
+                json!({"before": [1], "after": [1, 2]}),
+            ),
+        ] {
+            let actual = diff_json(&before, &after);
+            if actual != expected {
+                println!("Diffing {before} and {after}, expected {expected} but got {actual}");
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+
+    #[test]
+    fn test_diff_needs_same_options() {
+        let mut model = unedited_model();
+        let mut timer = Timer::new("test snapshots", None);
+        let mut opts = StatsOptions::default();
+        for name in ["before", "after"] {
+            model
+                .take_snapshot(&mut timer, name.to_string(), &opts)
+                .unwrap();
+        }
+        opts.od.sampling = Sampling::RoadNetwork;
+        model
+            .take_snapshot(&mut timer, "other".to_string(), &opts)
+            .unwrap();
+
+        let mut ok = true;
+        for (before, after, expected_ok) in [
+            ("before", "after", true),
+            ("before", "other", false),
+            ("other", "after", false),
+        ] {
+            if model.diff_snapshots(before, after).is_ok() != expected_ok {
+                println!("Diffing {before} and {after} should succeed: {expected_ok}");
+                ok = false;
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+}
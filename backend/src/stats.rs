@@ -1,86 +1,406 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::sync::Arc;
+
 use anyhow::Result;
-use enum_map::EnumMap;
-use graph::Timer;
+use enum_map::{EnumArray, EnumMap};
+use graph::{RoadID, Timer};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    coverage::NetworkDistances,
+    od::{CountsOD, ODOptions},
+    places::SubArea,
+    reachable::{Reachability, ReachabilityOptions},
+    utils::Quantiles,
+    InfraType, LevelOfService, MapModel, Tier,
+};
+
+/// The OD options, plus limits on reachability
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsOptions {
+    #[serde(flatten)]
+    pub od: ODOptions,
+    #[serde(default)]
+    pub reachability: ReachabilityOptions,
+    /// Report the population within each of these distances of the network, in meters along
+    /// roads
+    #[serde(default = "default_coverage_meters")]
+    pub coverage_meters: Vec<f64>,
+    /// Also report stats for each LAD or region inside the study area
+    #[serde(default)]
+    pub by_area: bool,
+}
+
+impl Default for StatsOptions {
+    fn default() -> Self {
+        Self {
+            od: ODOptions::default(),
+            reachability: ReachabilityOptions::default(),
+            coverage_meters: default_coverage_meters(),
+            by_area: false,
+        }
+    }
+}
+
+// Roughly a 3 and 5 minute walk
+fn default_coverage_meters() -> Vec<f64> {
+    vec![250.0, 400.0]
+}
 
-use crate::{utils::Quintiles, InfraType, LevelOfService, MapModel};
+/// Coverage is also reported for destinations within each of these connection distances, in
+/// meters
+const DISTANCE_BANDS: [f64; 4] = [250.0, 500.0, 1000.0, 2000.0];
+
+/// The fraction of each kind of destination that's reachable
+struct Coverage {
+    schools: f64,
+    gp_hospitals: f64,
+    town_centres: f64,
+    // Weighted by population, not just count
+    imd_population: f64,
+    population: f64,
+}
 
 impl MapModel {
     /// After any edit, calculate summary stats. Returns JSON.
-    pub fn recalculate_stats(&mut self, timer: &mut Timer) -> Result<String> {
-        let mut out = serde_json::Map::new();
-
-        self.recalculate_router(timer);
+    pub fn recalculate_stats(&mut self, timer: &mut Timer, opts: &StatsOptions) -> Result<String> {
+        let (out, _) = self.calculate_stats(timer, opts)?;
+        Ok(serde_json::to_string(&out)?)
+    }
 
+    /// Calculate summary stats, also returning the OD counts they're based on
+    pub(crate) fn calculate_stats(
+        &mut self,
+        timer: &mut Timer,
+        opts: &StatsOptions,
+    ) -> Result<(serde_json::Map<String, serde_json::Value>, Arc<CountsOD>)> {
         timer.step("calculate reachable network");
-        let roads = self.get_reachable_network();
+        let roads = self.reachable_network(&opts.reachability);
+        let od = self.cached_od_counts(timer, &opts.od)?;
+        let flow_quintiles = self.flow_quintiles();
+        timer.step("measure distances from the network");
+        let network_distances = self.network_distances(&opts.coverage_meters);
+
+        timer.step("calculate stats for the whole area");
+        let mut out = self.area_stats(&roads, &od, &flow_quintiles, &network_distances, opts, None);
+
+        out.insert(
+            "worst_directness_routes".to_string(),
+            serde_json::to_value(&od.worst_directness_routes)?,
+        );
+        out.insert(
+            "scenario".to_string(),
+            serde_json::to_value(opts.od.scenario)?,
+        );
+        out.insert(
+            "directness_baseline".to_string(),
+            serde_json::to_value(opts.od.directness_baseline)?,
+        );
+        out.insert(
+            "flow_quintile_labels".to_string(),
+            flow_quintiles.labels().into(),
+        );
+
+        if opts.by_area {
+            let mut by_area = Vec::new();
+            for area in &self.sub_areas {
+                timer.step(&format!("calculate stats for {}", area.name));
+                by_area.push(serde_json::json!({
+                    "name": area.name,
+                    "kind": area.kind,
+                    "stats": self.area_stats(
+                        &roads,
+                        &od,
+                        &flow_quintiles,
+                        &network_distances,
+                        opts,
+                        Some(area),
+                    ),
+                }));
+            }
+            out.insert("by_area".to_string(), by_area.into());
+        }
+
+        Ok((out, od))
+    }
+
+    /// Everything that can be limited to one sub-area, or the whole area for `None`. Destinations
+    /// and data zones must be inside the sub-area, but can be reached through roads outside it.
+    /// OD and flow stats only count roads inside the sub-area.
+    fn area_stats(
+        &self,
+        roads: &Reachability,
+        od: &CountsOD,
+        flow_quintiles: &Quantiles,
+        network_distances: &[NetworkDistances],
+        opts: &StatsOptions,
+        area: Option<&SubArea>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let mut out = serde_json::Map::new();
+
+        let coverage = self.reachable_coverage(roads, f64::MAX, area);
         out.insert(
             "percent_reachable_schools".to_string(),
-            percent(
-                self.schools.iter().filter(|x| roads.covers(x.road)).count(),
-                self.schools.len(),
-            )
-            .into(),
+            coverage.schools.into(),
         );
         out.insert(
             "percent_reachable_gp_hospitals".to_string(),
-            percent(
-                self.gp_hospitals
-                    .iter()
-                    .filter(|x| roads.covers(x.road))
-                    .count(),
-                self.gp_hospitals.len(),
-            )
-            .into(),
+            coverage.gp_hospitals.into(),
         );
         out.insert(
             "percent_reachable_town_centres".to_string(),
-            percent(
-                self.town_centres
-                    .iter()
-                    .filter(|x| roads.covers_any(&x.roads))
-                    .count(),
-                self.town_centres.len(),
-            )
-            .into(),
+            coverage.town_centres.into(),
+        );
+        out.insert(
+            "percent_reachable_imd_population".to_string(),
+            coverage.imd_population.into(),
+        );
+        out.insert(
+            "percent_reachable_population".to_string(),
+            coverage.population.into(),
+        );
+        out.insert(
+            "reachable_by_distance".to_string(),
+            DISTANCE_BANDS
+                .into_iter()
+                .map(|max_meters| {
+                    let coverage = self.reachable_coverage(roads, max_meters, area);
+                    serde_json::json!({
+                        "max_connection_meters": max_meters,
+                        "percent_reachable_schools": coverage.schools,
+                        "percent_reachable_gp_hospitals": coverage.gp_hospitals,
+                        "percent_reachable_town_centres": coverage.town_centres,
+                        "percent_reachable_imd_population": coverage.imd_population,
+                        "percent_reachable_population": coverage.population,
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into(),
+        );
+
+        out.insert(
+            "network_length".to_string(),
+            self.network_length_stats(area),
+        );
+        out.insert(
+            "population_coverage".to_string(),
+            self.population_coverage(network_distances, &opts.coverage_meters, area),
+        );
+
+        out.extend(self.od_stats(od, area));
+        let mut od_purposes = serde_json::Map::new();
+        for (purpose, purpose_od) in &od.per_purpose {
+            od_purposes.insert(
+                format!("{purpose:?}"),
+                serde_json::Value::Object(self.od_stats(purpose_od, area)),
+            );
+        }
+        out.insert(
+            "od_purposes".to_string(),
+            serde_json::Value::Object(od_purposes),
+        );
+
+        out.insert("equity".to_string(), self.equity_breakdown(roads, od, area));
+
+        // Quintiles are always from the whole area, so sub-areas can be compared
+        let mut covered_quintile_sums = [0; 5];
+        let mut total_quintile_sums = [0; 5];
+        for (idx, flow) in self.precalculated_flows.iter().enumerate() {
+            if !area.is_none_or(|a| a.contains_road(RoadID(idx))) {
+                continue;
+            }
+            let quintile = flow_quintiles.bin(*flow);
+            total_quintile_sums[quintile - 1] += *flow;
+            // TODO Check definition here -- should this look at LoS, so small high-flow roads are
+            // fine?
+            let covered = self.infra_types[idx].is_some();
+            if covered {
+                covered_quintile_sums[quintile - 1] += *flow;
+            }
+        }
+        out.insert(
+            "covered_flow_quintile_sums".to_string(),
+            covered_quintile_sums.to_vec().into(),
+        );
+        out.insert(
+            "total_flow_quintile_sums".to_string(),
+            total_quintile_sums.to_vec().into(),
         );
 
-        // Weighted by population, not just count
+        out
+    }
+
+    /// How long is the drawn network, broken down in different ways, and how much of the core
+    /// network does it cover with a high level of service? All lengths are in meters.
+    fn network_length_stats(&self, area: Option<&SubArea>) -> serde_json::Value {
+        let mut total = 0.0;
+        let mut by_tier: EnumMap<Tier, f64> = EnumMap::default();
+        let mut by_infra_type: EnumMap<InfraType, f64> = EnumMap::default();
+        let mut by_los: EnumMap<LevelOfService, f64> = EnumMap::default();
+        // Roads already with infrastructure in OSM, versus proposals
+        let mut existing = 0.0;
+        let mut new = 0.0;
+        let mut adequate_roads = HashSet::new();
+
+        // Routes may overlap. Like infra_types, the last route over a road decides its tier.
+        let mut road_tiers: HashMap<RoadID, Tier> = HashMap::new();
+        for route in self.routes.values() {
+            for (r, _) in &route.roads {
+                road_tiers.insert(*r, route.tier);
+            }
+        }
+
+        for (r, tier) in road_tiers {
+            if !area.is_none_or(|a| a.contains_road(r)) {
+                continue;
+            }
+            let Some(infra_type) = self.infra_types[r.0] else {
+                continue;
+            };
+            let road = &self.graph.roads[r.0];
+            let length = road.length_meters;
+            total += length;
+            by_tier[tier] += length;
+            by_infra_type[infra_type] += length;
+            by_los[self.los[r.0]] += length;
+            if crate::existing::classify(&road.osm_tags)
+                .is_some_and(|x| x != InfraType::MixedTraffic)
+            {
+                existing += length;
+            } else {
+                new += length;
+            }
+            if self.los[r.0] == LevelOfService::High {
+                adequate_roads.insert(r);
+            }
+        }
+
+        let mut core_total: EnumMap<Tier, f64> = EnumMap::default();
+        let mut core_covered: EnumMap<Tier, f64> = EnumMap::default();
+        for (idx, tier) in self.core_network.iter().enumerate() {
+            if !area.is_none_or(|a| a.contains_road(RoadID(idx))) {
+                continue;
+            }
+            if let Some(tier) = tier {
+                let length = self.graph.roads[idx].length_meters;
+                core_total[*tier] += length;
+                if adequate_roads.contains(&RoadID(idx)) {
+                    core_covered[*tier] += length;
+                }
+            }
+        }
+        let core_total_sum: f64 = core_total.values().sum();
+        let core_covered_sum: f64 = core_covered.values().sum();
+
+        let mut percent_core_by_tier: EnumMap<Tier, f64> = EnumMap::default();
+        for (tier, length) in core_total {
+            percent_core_by_tier[tier] = percent_f64(core_covered[tier], length);
+        }
+
+        serde_json::json!({
+            "total": total,
+            "by_tier": enum_map_to_json(by_tier),
+            "by_infra_type": enum_map_to_json(by_infra_type),
+            "by_los": enum_map_to_json(by_los),
+            "existing": existing,
+            "new": new,
+            "core_network_total": core_total_sum,
+            "core_network_covered": core_covered_sum,
+            "percent_core_network_covered": percent_f64(core_covered_sum, core_total_sum),
+            "percent_core_network_covered_by_tier": enum_map_to_json(percent_core_by_tier),
+        })
+    }
+
+    /// Which destinations are reachable within a connection distance of the network?
+    fn reachable_coverage(
+        &self,
+        roads: &Reachability,
+        max_meters: f64,
+        area: Option<&SubArea>,
+    ) -> Coverage {
+        let within = |distance: Option<f64>| distance.is_some_and(|d| d <= max_meters);
+        let in_area = |r: RoadID| area.is_none_or(|a| a.contains_road(r));
+
         let mut deprived_sum = 0;
         let mut deprived_total = 0;
         let mut population_sum = 0;
         let mut population_total = 0;
         for zone in &self.data_zones {
+            if !area.is_none_or(|a| a.contains_data_zone(zone)) {
+                continue;
+            }
+            let covered = within(roads.connection_distance_any(&zone.roads));
             // Only the first quintile
             if zone.imd_percentile <= 20 {
                 deprived_total += zone.population;
-                if roads.covers_any(&zone.roads) {
+                if covered {
                     deprived_sum += zone.population;
                 }
             }
 
             population_total += zone.population;
-            if roads.covers_any(&zone.roads) {
+            if covered {
                 population_sum += zone.population;
             }
         }
-        out.insert(
-            "percent_reachable_imd_population".to_string(),
-            percent(deprived_sum, deprived_total).into(),
-        );
-        out.insert(
-            "percent_reachable_population".to_string(),
-            percent(population_sum, population_total).into(),
-        );
 
-        timer.step("calculate OD routes and stats");
+        let schools: Vec<_> = self.schools.iter().filter(|x| in_area(x.road)).collect();
+        let gp_hospitals: Vec<_> = self
+            .gp_hospitals
+            .iter()
+            .filter(|x| in_area(x.road))
+            .collect();
+        let town_centres: Vec<_> = self
+            .town_centres
+            .iter()
+            .filter(|x| x.roads.iter().any(|r| in_area(*r)))
+            .collect();
+
+        Coverage {
+            schools: percent(
+                schools
+                    .iter()
+                    .filter(|x| within(roads.connection_distance(x.road)))
+                    .count(),
+                schools.len(),
+            ),
+            gp_hospitals: percent(
+                gp_hospitals
+                    .iter()
+                    .filter(|x| within(roads.connection_distance(x.road)))
+                    .count(),
+                gp_hospitals.len(),
+            ),
+            town_centres: percent(
+                town_centres
+                    .iter()
+                    .filter(|x| within(roads.connection_distance_any(&x.roads)))
+                    .count(),
+                town_centres.len(),
+            ),
+            imd_population: percent(deprived_sum, deprived_total),
+            population: percent(population_sum, population_total),
+        }
+    }
+
+    /// Summarize OD counts for one trip purpose or all of them
+    fn od_stats(
+        &self,
+        od: &CountsOD,
+        area: Option<&SubArea>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let mut out = serde_json::Map::new();
 
         let mut count_by_infra: EnumMap<InfraType, usize> = EnumMap::default();
         let mut count_by_los: EnumMap<LevelOfService, usize> = EnumMap::default();
         let mut count_off_network = 0;
         let mut total_count = 0;
 
-        let od = self.od_counts()?;
-        for (r, count) in od.counts {
+        for (r, count) in &od.counts {
+            if !area.is_none_or(|a| a.contains_road(*r)) {
+                continue;
+            }
             total_count += count;
             if let Some(infra_type) = self.infra_types[r.0] {
                 count_by_infra[infra_type] += count;
@@ -115,36 +435,32 @@ impl MapModel {
             "od_percents_los".to_string(),
             serde_json::Value::Object(od_percents_los),
         );
-        out.insert(
-            "average_weighted_directness".to_string(),
-            od.average_weighted_directness.into(),
-        );
-        out.insert(
-            "worst_directness_routes".to_string(),
-            serde_json::to_value(&od.worst_directness_routes)?,
-        );
-
-        let flow_stats = Quintiles::new(&self.precalculated_flows);
-        let mut covered_quintile_sums = [0; 5];
-        for (idx, flow) in self.precalculated_flows.iter().enumerate() {
-            // TODO Check definition here -- should this look at LoS, so small high-flow roads are
-            // fine?
-            let covered = self.infra_types[idx].is_some();
-            if covered {
-                let quintile = flow_stats.quintile(*flow);
-                covered_quintile_sums[quintile - 1] += *flow;
-            }
+        // Directness is per trip, not per road, so it's only reported for the whole area
+        if area.is_none() {
+            out.insert(
+                "average_weighted_directness".to_string(),
+                od.average_weighted_directness.into(),
+            );
         }
-        out.insert(
-            "covered_flow_quintile_sums".to_string(),
-            covered_quintile_sums.to_vec().into(),
-        );
-        out.insert(
-            "total_flow_quintile_sums".to_string(),
-            flow_stats.total_quintile_sums.to_vec().into(),
-        );
 
-        Ok(serde_json::to_string(&out)?)
+        out
+    }
+}
+
+/// Keyed by the debug name of each variant
+fn enum_map_to_json<K: EnumArray<f64> + Debug>(map: EnumMap<K, f64>) -> serde_json::Value {
+    serde_json::Value::Object(
+        map.into_iter()
+            .map(|(key, value)| (format!("{key:?}"), value.into()))
+            .collect(),
+    )
+}
+
+fn percent_f64(x: f64, total: f64) -> f64 {
+    if total == 0.0 {
+        0.0
+    } else {
+        x / total
     }
 }
 
@@ -1,30 +1,164 @@
-/// Given stats about a route, calculate its "uptake", between 0 and 1.
+use serde::{Deserialize, Serialize};
+
+use crate::od::TripPurpose;
+
+/// The uptake scenarios from the PCT
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Scenario {
+    /// Cycling today, from the desire lines. The route doesn't matter.
+    Baseline,
+    /// Cycling at Dutch levels, accounting for distance and hilliness
+    #[default]
+    GoDutch,
+    /// Go Dutch, with e-bikes making longer and hillier trips more likely
+    Ebike,
+    /// Cycling today, plus an increase that doubles cycling nationally. Routes with a higher
+    /// propensity to cycle get more of the increase.
+    GovTarget,
+}
+
+impl Scenario {
+    /// The model for one trip purpose. The PCT only has models for commutes and travel to
+    /// school. Like the NPT, utility and leisure trips use the commute models as a proxy, since
+    /// they're also made by adults and depend on distance and hilliness in a similar way.
+    pub(crate) fn model(self, purpose: TripPurpose) -> UptakeModel {
+        let coefficients = match (self, purpose) {
+            (Scenario::Baseline, _) => None,
+            // There's no e-bike scenario for school trips, so this is the same as Go Dutch
+            (Scenario::GoDutch | Scenario::Ebike, TripPurpose::School) => Some(&SCHOOL_GO_DUTCH),
+            (Scenario::GovTarget, TripPurpose::School) => Some(&SCHOOL_GOV_TARGET),
+            // Commute, utility and leisure trips
+            (Scenario::GoDutch, _) => Some(&GO_DUTCH),
+            (Scenario::Ebike, _) => Some(&EBIKE),
+            (Scenario::GovTarget, _) => Some(&GOV_TARGET),
+        };
+        UptakeModel {
+            scenario: self,
+            coefficients,
+        }
+    }
+}
+
+/// One scenario's model for one trip purpose
+pub(crate) struct UptakeModel {
+    scenario: Scenario,
+    /// Only `None` for the baseline
+    coefficients: Option<&'static Coefficients>,
+}
+
+impl UptakeModel {
+    /// Given stats about a route, calculate its "uptake", between 0 and 1. The gradient is the
+    /// length-weighted average of the absolute gradient along the route, as a percent.
+    /// `current_cycling` is the fraction of trips on the desire line cycled today.
+    pub fn uptake(&self, distance_meters: f64, gradient_percent: f64, current_cycling: f64) -> f64 {
+        let modelled = || {
+            self.coefficients
+                .map_or(0.0, |c| c.uptake(distance_meters, gradient_percent))
+        };
+        match self.scenario {
+            Scenario::Baseline => current_cycling,
+            Scenario::GoDutch | Scenario::Ebike => modelled(),
+            // The govtarget model gives the increase, which the PCT adds to current cycling
+            Scenario::GovTarget => (current_cycling + modelled()).min(1.0),
+        }
+    }
+}
+
+/// The terms of the logit models used by the PCT
 // Ported from
 // https://github.com/itsleeds/pct/blob/e630464efeaef539b18647b10745b863c9cd9948/R/uptake.R#L216
-pub fn pct_godutch_2020(distance_meters: f64) -> f64 {
-    // TODO Find a data source and calculate this
-    let gradient_percent = 0.0;
-
-    let alpha = -4.018 + 2.550;
-    let d1 = -0.6369 - 0.08036;
-    let d2 = 1.988;
-    let d3 = 0.008775;
-    let h1 = -0.2555;
-    let h2 = -0.78;
-    let i1 = 0.02006;
-    let i2 = -0.1234;
-
-    let gradient_percent = gradient_percent + h2;
-    let distance_km = (distance_meters / 1000.0).min(30.0);
-
-    let p = alpha
-        + (d1 * distance_km)
-        + (d2 * distance_km.sqrt())
-        + (d3 * distance_km.powi(2))
-        + (h1 * gradient_percent)
-        + (i1 * distance_km * gradient_percent)
-        + (i2 * distance_km.sqrt() * gradient_percent);
-    inverse_logit(p)
+struct Coefficients {
+    alpha: f64,
+    d1: f64,
+    d2: f64,
+    d3: f64,
+    h1: f64,
+    h2: f64,
+    i1: f64,
+    i2: f64,
+    max_distance_km: f64,
+}
+
+// The defaults of uptake_pct_govtarget_2020
+const GOV_TARGET: Coefficients = Coefficients {
+    alpha: -4.018,
+    d1: -0.6369,
+    d2: 1.988,
+    d3: 0.008775,
+    h1: -0.2555,
+    h2: -0.78,
+    i1: 0.02006,
+    i2: -0.1234,
+    max_distance_km: 30.0,
+};
+
+// The defaults of uptake_pct_godutch_2020, which adjust the govtarget model
+const GO_DUTCH: Coefficients = Coefficients {
+    alpha: -4.018 + 2.550,
+    d1: -0.6369 - 0.08036,
+    d2: 1.988,
+    d3: 0.008775,
+    h1: -0.2555,
+    h2: -0.78,
+    i1: 0.02006,
+    i2: -0.1234,
+    max_distance_km: 30.0,
+};
+
+// Go Dutch, with the e-bike adjustments to the distance and gradient terms from the PCT methods
+// (Lovelace et al, 2017)
+const EBIKE: Coefficients = Coefficients {
+    alpha: -4.018 + 2.550,
+    d1: -0.6369 - 0.08036 + 0.05509,
+    d2: 1.988,
+    d3: 0.008775 - 0.000295,
+    h1: -0.2555 + 0.1812,
+    h2: -0.78,
+    i1: 0.02006,
+    i2: -0.1234,
+    max_distance_km: 30.0,
+};
+
+// The defaults of uptake_pct_govtarget_school2. Distance isn't capped.
+const SCHOOL_GOV_TARGET: Coefficients = Coefficients {
+    alpha: -7.178,
+    d1: -1.870,
+    d2: 5.499,
+    d3: 0.0,
+    h1: -0.6465,
+    h2: 0.0,
+    i1: 0.0,
+    i2: 0.0,
+    max_distance_km: f64::MAX,
+};
+
+// The defaults of uptake_pct_godutch_school2
+const SCHOOL_GO_DUTCH: Coefficients = Coefficients {
+    alpha: -7.178 + 3.574,
+    d1: -1.870 + 0.3438,
+    d2: 5.499,
+    d3: 0.0,
+    h1: -0.6465,
+    h2: 0.0,
+    i1: 0.0,
+    i2: 0.0,
+    max_distance_km: f64::MAX,
+};
+
+impl Coefficients {
+    fn uptake(&self, distance_meters: f64, gradient_percent: f64) -> f64 {
+        let gradient_percent = gradient_percent + self.h2;
+        let distance_km = (distance_meters / 1000.0).min(self.max_distance_km);
+
+        let p = self.alpha
+            + (self.d1 * distance_km)
+            + (self.d2 * distance_km.sqrt())
+            + (self.d3 * distance_km.powi(2))
+            + (self.h1 * gradient_percent)
+            + (self.i1 * distance_km * gradient_percent)
+            + (self.i2 * distance_km.sqrt() * gradient_percent);
+        inverse_logit(p)
+    }
 }
 
 fn inverse_logit(p: f64) -> f64 {
@@ -34,3 +168,82 @@ fn inverse_logit(p: f64) -> f64 {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Scenario::*;
+    use TripPurpose::*;
+
+    #[test]
+    fn test_scenarios() {
+        let mut ok = true;
+        // Rounded to 6 decimal places. data_prep/uptake_reference.R prints these rows by calling
+        // pct::uptake_pct_godutch_2020 and the other functions, along with the pct version used.
+        // The values here were worked out from the formulas in uptake.R at the commit cited
+        // above; run the script to check them against the package.
+        // The fifth value is the fraction cycled today
+        for (scenario, purpose, distance_meters, gradient_percent, current, expected) in [
+            (GoDutch, Commute, 1000.0, 0.0, 0.0, 0.522837),
+            (GoDutch, Commute, 5000.0, 0.0, 0.0, 0.486668),
+            (GoDutch, Commute, 5000.0, 2.0, 0.0, 0.285855),
+            (GoDutch, Commute, 10000.0, 1.0, 0.0, 0.171587),
+            (GoDutch, Commute, 10000.0, 5.0, 0.0, 0.033734),
+            (GoDutch, Commute, 2500.0, 7.0, 0.0, 0.072166),
+            // Distance is capped at 30km
+            (GoDutch, Commute, 40000.0, 3.0, 0.0, 0.007166),
+            // Current cycling doesn't affect Go Dutch
+            (GoDutch, Commute, 5000.0, 2.0, 0.1, 0.285855),
+            (Ebike, Commute, 1000.0, 0.0, 0.0, 0.501217),
+            (Ebike, Commute, 5000.0, 2.0, 0.0, 0.394971),
+            (Ebike, Commute, 10000.0, 5.0, 0.0, 0.112161),
+            // Only current cycling matters for the baseline
+            (Baseline, Commute, 1000.0, 0.0, 0.0, 0.0),
+            (Baseline, Commute, 5000.0, 2.0, 0.03, 0.03),
+            (Baseline, School, 3000.0, 2.0, 0.2, 0.2),
+            // The modelled increase from uptake_pct_govtarget_2020, added to current cycling
+            (GovTarget, Commute, 1000.0, 0.0, 0.0, 0.084848),
+            (GovTarget, Commute, 1000.0, 0.0, 0.05, 0.134848),
+            (GovTarget, Commute, 5000.0, 2.0, 0.03, 0.074625),
+            (GovTarget, Commute, 10000.0, 5.0, 0.01, 0.016052),
+            (GovTarget, Commute, 1000.0, 0.0, 0.95, 1.0),
+            (GoDutch, School, 1000.0, 0.0, 0.0, 0.591169),
+            (GoDutch, School, 3000.0, 2.0, 0.0, 0.512234),
+            (Ebike, School, 1000.0, 0.0, 0.0, 0.591169),
+            (Ebike, School, 3000.0, 2.0, 0.0, 0.512234),
+            // The modelled increase from uptake_pct_govtarget_school2
+            (GovTarget, School, 1000.0, 0.0, 0.0, 0.02795),
+            (GovTarget, School, 3000.0, 2.0, 0.1, 0.11039),
+        ] {
+            let actual = scenario
+                .model(purpose)
+                .uptake(distance_meters, gradient_percent, current);
+            if (actual - expected).abs() > 1e-6 {
+                println!("For {scenario:?} {purpose:?} trips with {distance_meters}m, {gradient_percent}% and {current} cycling today, expected {expected} but got {actual}");
+                ok = false;
+            }
+        }
+
+        // Utility and leisure trips use the commute models
+        for scenario in [Baseline, GoDutch, Ebike, GovTarget] {
+            let commute = scenario.model(Commute);
+            for purpose in [Utility, Leisure] {
+                let model = scenario.model(purpose);
+                for (distance_meters, gradient_percent, current) in
+                    [(1000.0, 0.0, 0.0), (5000.0, 2.0, 0.03), (10000.0, 5.0, 0.1)]
+                {
+                    let expected = commute.uptake(distance_meters, gradient_percent, current);
+                    let actual = model.uptake(distance_meters, gradient_percent, current);
+                    if actual != expected {
+                        println!("{scenario:?} {purpose:?} trips with {distance_meters}m, {gradient_percent}% and {current} cycling today should match commutes, with {expected}, but got {actual}");
+                        ok = false;
+                    }
+                }
+            }
+        }
+
+        if !ok {
+            panic!("Some cases failed");
+        }
+    }
+}
@@ -1,47 +1,183 @@
-pub struct Quintiles {
-    // 20% of values are >= this amount
-    quintile1: usize,
-    // 40% of values are >= this amount
-    quintile2: usize,
-    quintile3: usize,
-    quintile4: usize,
-    // quintile5 is the minimum value
-    pub total_quintile_sums: [usize; 5],
+/// Splits values into a number of bins, each with roughly the same total weight. Bin 1 has the
+/// highest values. Equal values always land in the same bin, so with many ties, some bins may be
+/// bigger than others or empty.
+pub struct Quantiles {
+    // The smallest value in each bin, or None if the bin is empty. Never increasing.
+    thresholds: Vec<Option<usize>>,
+    /// The sum of values in each bin
+    pub sums: Vec<usize>,
+    /// The sum of weights in each bin. Without weights, the number of values.
+    pub weights: Vec<usize>,
+    // The (min, max) value in each bin
+    ranges: Vec<Option<(usize, usize)>>,
 }
 
-impl Quintiles {
-    pub fn new(values: &Vec<usize>) -> Self {
-        let mut sorted = values.clone();
-        sorted.sort();
-        sorted.reverse();
-        let n = ((sorted.len() as f64) / 5.0).ceil() as usize;
+impl Quantiles {
+    /// Every value has the same weight
+    pub fn new(num_bins: usize, values: &[usize]) -> Self {
+        Self::weighted(num_bins, values.iter().map(|x| (*x, 1)))
+    }
+
+    /// Each input is a (value, weight) pair. Bins are split by weight, so population-weighted
+    /// quintiles each cover about 20% of the population.
+    pub fn weighted(num_bins: usize, values: impl IntoIterator<Item = (usize, usize)>) -> Self {
+        assert!(num_bins > 0);
+        let mut sorted: Vec<(usize, usize)> = values.into_iter().collect();
+        // Highest first
+        sorted.sort_by(|a, b| b.0.cmp(&a.0));
+        let total_weight: usize = sorted.iter().map(|(_, weight)| *weight).sum();
+
+        let mut thresholds = vec![None; num_bins];
+        let mut cumulative_weight = 0;
+        for (value, weight) in &sorted {
+            // Which bin is this by position? Use the weight before this value, so a value with
+            // all of the weight still lands in the first bin.
+            let bin = if total_weight == 0 {
+                0
+            } else {
+                (cumulative_weight * num_bins / total_weight).min(num_bins - 1)
+            };
+            thresholds[bin] = Some(*value);
+            cumulative_weight += weight;
+        }
+
+        let mut quantiles = Self {
+            thresholds,
+            sums: vec![0; num_bins],
+            weights: vec![0; num_bins],
+            ranges: vec![None; num_bins],
+        };
+        // Ties may put a value in an earlier bin than its position, so recalculate everything
+        // from the final bins
+        for (value, weight) in sorted {
+            let idx = quantiles.bin(value) - 1;
+            quantiles.sums[idx] += value;
+            quantiles.weights[idx] += weight;
+            quantiles.ranges[idx] = Some(match quantiles.ranges[idx] {
+                Some((min, max)) => (min.min(value), max.max(value)),
+                None => (value, value),
+            });
+        }
+        quantiles
+    }
+
+    pub fn num_bins(&self) -> usize {
+        self.thresholds.len()
+    }
+
+    /// Returns [1, num_bins], with 1 for the highest values. Values lower than anything in the
+    /// input, or anything when the input was empty, are in the last bin.
+    pub fn bin(&self, value: usize) -> usize {
+        for (idx, threshold) in self.thresholds.iter().enumerate() {
+            if threshold.is_some_and(|x| value >= x) {
+                return idx + 1;
+            }
+        }
+        self.num_bins()
+    }
+
+    /// Describe a bin (1-indexed), like "2nd quintile (150-299)"
+    pub fn label(&self, bin: usize) -> String {
+        let name = match self.num_bins() {
+            4 => "quartile",
+            5 => "quintile",
+            10 => "decile",
+            _ => "quantile",
+        };
+        let ordinal = match (bin % 10, bin % 100) {
+            (1, x) if x != 11 => format!("{bin}st"),
+            (2, x) if x != 12 => format!("{bin}nd"),
+            (3, x) if x != 13 => format!("{bin}rd"),
+            _ => format!("{bin}th"),
+        };
+        match self.ranges.get(bin.wrapping_sub(1)).cloned().flatten() {
+            Some((min, max)) if min == max => format!("{ordinal} {name} ({min})"),
+            Some((min, max)) => format!("{ordinal} {name} ({min}-{max})"),
+            None => format!("{ordinal} {name} (empty)"),
+        }
+    }
+
+    /// Labels for every bin, in order
+    pub fn labels(&self) -> Vec<String> {
+        (1..=self.num_bins()).map(|bin| self.label(bin)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let mut total_quintile_sums = [0; 5];
-        for (idx, x) in sorted.iter().enumerate() {
-            total_quintile_sums[idx / n] += *x;
+    #[test]
+    fn test_quantiles() {
+        let mut ok = true;
+        for (description, quantiles, expected_bins, expected_sums) in [
+            (
+                "one value each",
+                Quantiles::new(5, &[10, 20, 30, 40, 50]),
+                vec![(50, 1), (40, 2), (30, 3), (20, 4), (10, 5), (0, 5)],
+                vec![50, 40, 30, 20, 10],
+            ),
+            (
+                "empty",
+                Quantiles::new(5, &[]),
+                vec![(0, 5), (100, 5)],
+                vec![0, 0, 0, 0, 0],
+            ),
+            (
+                "fewer values than bins",
+                Quantiles::new(5, &[7, 3]),
+                vec![(7, 1), (5, 3), (3, 3), (1, 5)],
+                vec![7, 0, 3, 0, 0],
+            ),
+            (
+                "all ties",
+                Quantiles::new(5, &[4, 4, 4, 4, 4, 4]),
+                vec![(4, 1), (3, 5)],
+                vec![24, 0, 0, 0, 0],
+            ),
+            (
+                "weighted",
+                Quantiles::weighted(2, [(100, 8), (50, 1), (10, 1)]),
+                vec![(100, 1), (50, 2), (10, 2)],
+                vec![100, 60],
+            ),
+        ] {
+            for (value, expected) in expected_bins {
+                let actual = quantiles.bin(value);
+                if actual != expected {
+                    println!(
+                        "For {description}, {value} should be in bin {expected}, but got {actual}"
+                    );
+                    ok = false;
+                }
+            }
+            if quantiles.sums != expected_sums {
+                println!(
+                    "For {description}, expected sums {expected_sums:?} but got {:?}",
+                    quantiles.sums
+                );
+                ok = false;
+            }
         }
 
-        Self {
-            quintile1: sorted[n],
-            quintile2: sorted[n * 2],
-            quintile3: sorted[n * 3],
-            quintile4: sorted[n * 4],
-            total_quintile_sums,
+        if !ok {
+            panic!("Some cases failed");
         }
     }
 
-    // Returns [1, 5]
-    pub fn quintile(&self, value: usize) -> usize {
-        if value >= self.quintile1 {
-            1
-        } else if value >= self.quintile2 {
-            2
-        } else if value >= self.quintile3 {
-            3
-        } else if value >= self.quintile4 {
-            4
-        } else {
-            5
+    #[test]
+    fn test_labels() {
+        let quantiles = Quantiles::new(5, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        let actual = quantiles.labels();
+        let expected = vec![
+            "1st quintile (9-10)",
+            "2nd quintile (7-8)",
+            "3rd quintile (5-6)",
+            "4th quintile (3-4)",
+            "5th quintile (1-2)",
+        ];
+        if actual != expected {
+            panic!("Expected {expected:?} but got {actual:?}");
         }
     }
 }
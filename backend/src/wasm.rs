@@ -1,5 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Once;
+use std::time::Duration;
 
 use geo::{Coord, LineString, Polygon};
 use geojson::{Feature, FeatureCollection, Geometry};
@@ -7,7 +8,11 @@ use graph::{IntersectionID, RoadID, Timer};
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 
-use crate::{evaluate::Breakdown, Dir, Highway, InfraType, MapModel, Route, Tier};
+use crate::{
+    connectivity::GapOptions, directness::DirectnessBaseline, evaluate::Breakdown, od::ODOptions,
+    outcomes::OutcomesConfig, reachable::ReachabilityOptions, snapshots::Snapshot,
+    stats::StatsOptions, Dir, Highway, InfraType, LevelOfService, MapModel, Route, Tier,
+};
 
 static START: Once = Once::new();
 
@@ -102,7 +107,7 @@ impl MapModel {
     }
 
     #[wasm_bindgen(js_name = evaluateRoute)]
-    pub fn evaluate_route_wasm(&self, input: JsValue) -> Result<String, JsValue> {
+    pub fn evaluate_route_wasm(&mut self, input: JsValue) -> Result<String, JsValue> {
         let req: EvaluateRouteRequest = serde_wasm_bindgen::from_value(input)?;
         self.evaluate_route(
             self.graph.mercator.pt_to_mercator(Coord {
@@ -122,6 +127,28 @@ impl MapModel {
                     return Err(err_to_js(format!("evaluateRoute got bad breakdown {x}")));
                 }
             },
+            req.compare_baseline,
+            req.directness_baseline,
+        )
+        .map_err(err_to_js)
+    }
+
+    /// Returns a GeoJSON string with the reached roads and an outline polygon. The limit is in
+    /// seconds of cycling.
+    #[wasm_bindgen(js_name = isochrone)]
+    pub fn isochrone_wasm(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let req: IsochroneRequest = serde_wasm_bindgen::from_value(input)?;
+        // Make sure the edge costs reflect any edits. This is cheap if nothing changed.
+        let mut timer = Timer::new("update router for isochrone", None);
+        self.recalculate_router(&mut timer);
+        timer.done();
+
+        self.isochrone(
+            self.graph
+                .mercator
+                .pt_to_mercator(Coord { x: req.x, y: req.y }),
+            Duration::from_secs_f64(req.limit_seconds),
+            req.min_los,
         )
         .map_err(err_to_js)
     }
@@ -157,36 +184,91 @@ impl MapModel {
     }
 
     #[wasm_bindgen(js_name = evaluateOD)]
-    pub fn evaluate_od_wasm(&self) -> Result<String, JsValue> {
-        self.evaluate_od().map_err(err_to_js)
+    pub fn evaluate_od_wasm(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let opts: ODOptions = serde_wasm_bindgen::from_value(input)?;
+        let mut timer = Timer::new("evaluate OD", None);
+        let result = self.evaluate_od(&mut timer, &opts).map_err(err_to_js);
+        timer.done();
+        result
+    }
+
+    #[wasm_bindgen(js_name = evaluateDesireLines)]
+    pub fn evaluate_desire_lines_wasm(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let mut opts: ODOptions = serde_wasm_bindgen::from_value(input)?;
+        opts.per_desire_line = true;
+        let mut timer = Timer::new("evaluate desire lines", None);
+        let out = self
+            .cached_od_counts(&mut timer, &opts)
+            .map_err(err_to_js)?;
+        timer.done();
+        self.desire_lines_to_geojson(&out.desire_lines)
+            .map_err(err_to_js)
     }
 
     #[wasm_bindgen(js_name = recalculateStats)]
-    pub fn recalculate_stats_wasm(&mut self) -> Result<String, JsValue> {
+    pub fn recalculate_stats_wasm(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let opts: StatsOptions = serde_wasm_bindgen::from_value(input)?;
         let mut timer = Timer::new("recalculate after edits", None);
-        let result = self.recalculate_stats(&mut timer).map_err(err_to_js);
+        let result = self.recalculate_stats(&mut timer, &opts).map_err(err_to_js);
+        timer.done();
+        result
+    }
+
+    #[wasm_bindgen(js_name = estimateOutcomes)]
+    pub fn estimate_outcomes_wasm(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let req: OutcomesRequest = serde_wasm_bindgen::from_value(input)?;
+        let mut timer = Timer::new("estimate outcomes", None);
+        let result = self
+            .estimate_outcomes(&mut timer, &req.od, &req.config)
+            .map_err(err_to_js);
         timer.done();
         result
     }
 
+    #[wasm_bindgen(js_name = takeSnapshot)]
+    pub fn take_snapshot_wasm(&mut self, name: String, input: JsValue) -> Result<String, JsValue> {
+        let opts: StatsOptions = serde_wasm_bindgen::from_value(input)?;
+        let mut timer = Timer::new("take snapshot", None);
+        let result = self
+            .take_snapshot(&mut timer, name, &opts)
+            .map_err(err_to_js);
+        timer.done();
+        result
+    }
+
+    #[wasm_bindgen(js_name = listSnapshots)]
+    pub fn list_snapshots_wasm(&self) -> Result<String, JsValue> {
+        self.list_snapshots().map_err(err_to_js)
+    }
+
+    #[wasm_bindgen(js_name = deleteSnapshot)]
+    pub fn delete_snapshot_wasm(&mut self, name: String) -> Result<(), JsValue> {
+        self.delete_snapshot(&name).map_err(err_to_js)
+    }
+
+    #[wasm_bindgen(js_name = restoreSnapshot)]
+    pub fn restore_snapshot_wasm(&mut self, name: String) -> Result<(), JsValue> {
+        self.restore_snapshot(&name).map_err(err_to_js)
+    }
+
+    #[wasm_bindgen(js_name = diffSnapshots)]
+    pub fn diff_snapshots_wasm(&self, before: String, after: String) -> Result<String, JsValue> {
+        self.diff_snapshots(&before, &after).map_err(err_to_js)
+    }
+
     #[wasm_bindgen(js_name = toSavefile)]
     pub fn to_savefile(&self) -> Result<String, JsValue> {
         serde_json::to_string(&Savefile {
             routes: self.routes.clone(),
             id_counter: self.id_counter,
+            snapshots: self.snapshots.clone(),
         })
         .map_err(err_to_js)
     }
 
     #[wasm_bindgen(js_name = loadSavefile)]
     pub fn load_savefile(&mut self, input: String) -> Result<(), JsValue> {
-        let savefile: Savefile = serde_json::from_str(&input).map_err(err_to_js)?;
-        // TODO Detect if the savefile is incompatible with the current model (too big RoadIDs) and
-        // bail cleanly
-        self.routes = savefile.routes;
-        self.id_counter = savefile.id_counter;
-        self.recalculate_after_edits();
-        Ok(())
+        self.apply_savefile(&input).map_err(err_to_js)
     }
 
     #[wasm_bindgen(js_name = meshDensity)]
@@ -209,10 +291,24 @@ impl MapModel {
         self.import_core_network()
     }
 
+    /// Returns GeoJSON with suggested routes joining parts of the network
+    #[wasm_bindgen(js_name = findNetworkGaps)]
+    pub fn find_network_gaps_wasm(&self, input: JsValue) -> Result<String, JsValue> {
+        let opts: GapOptions = serde_wasm_bindgen::from_value(input)?;
+        self.find_network_gaps(&opts).map_err(err_to_js)
+    }
+
+    /// Returns GeoJSON points where the network or reachable area meets a severance
+    #[wasm_bindgen(js_name = getSeveranceCrossings)]
+    pub fn get_severance_crossings(&mut self) -> Result<String, JsValue> {
+        let crossings = self.severance_crossings();
+        self.severance_crossings_to_geojson(&crossings)
+            .map_err(err_to_js)
+    }
+
     #[wasm_bindgen(js_name = getSchools)]
-    pub fn get_schools(&self) -> Result<String, JsValue> {
-        // TODO Some kind of caching would make this nicer
-        let roads = self.get_reachable_network();
+    pub fn get_schools(&mut self) -> Result<String, JsValue> {
+        let roads = self.reachable_network(&ReachabilityOptions::default());
 
         serde_json::to_string(&FeatureCollection {
             bbox: None,
@@ -221,16 +317,17 @@ impl MapModel {
                 .schools
                 .iter()
                 .enumerate()
-                .map(|(idx, s)| s.to_gj(&self.graph.mercator, roads.covers(s.road), idx))
+                .map(|(idx, s)| {
+                    s.to_gj(&self.graph.mercator, roads.connection_distance(s.road), idx)
+                })
                 .collect(),
         })
         .map_err(err_to_js)
     }
 
     #[wasm_bindgen(js_name = getGPHospitals)]
-    pub fn get_gp_hospitals(&self) -> Result<String, JsValue> {
-        // TODO Some kind of caching would make this nicer
-        let roads = self.get_reachable_network();
+    pub fn get_gp_hospitals(&mut self) -> Result<String, JsValue> {
+        let roads = self.reachable_network(&ReachabilityOptions::default());
 
         serde_json::to_string(&FeatureCollection {
             bbox: None,
@@ -239,16 +336,17 @@ impl MapModel {
                 .gp_hospitals
                 .iter()
                 .enumerate()
-                .map(|(idx, x)| x.to_gj(&self.graph.mercator, roads.covers(x.road), idx))
+                .map(|(idx, x)| {
+                    x.to_gj(&self.graph.mercator, roads.connection_distance(x.road), idx)
+                })
                 .collect(),
         })
         .map_err(err_to_js)
     }
 
     #[wasm_bindgen(js_name = getTownCentres)]
-    pub fn get_town_centres(&self) -> Result<String, JsValue> {
-        // TODO Some kind of caching would make this nicer
-        let roads = self.get_reachable_network();
+    pub fn get_town_centres(&mut self) -> Result<String, JsValue> {
+        let roads = self.reachable_network(&ReachabilityOptions::default());
 
         serde_json::to_string(&FeatureCollection {
             bbox: None,
@@ -257,16 +355,21 @@ impl MapModel {
                 .town_centres
                 .iter()
                 .enumerate()
-                .map(|(idx, x)| x.to_gj(&self.graph.mercator, roads.covers_any(&x.roads), idx))
+                .map(|(idx, x)| {
+                    x.to_gj(
+                        &self.graph.mercator,
+                        roads.connection_distance_any(&x.roads),
+                        idx,
+                    )
+                })
                 .collect(),
         })
         .map_err(err_to_js)
     }
 
     #[wasm_bindgen(js_name = getDataZones)]
-    pub fn get_data_zones(&self) -> Result<String, JsValue> {
-        // TODO Some kind of caching would make this nicer
-        let roads = self.get_reachable_network();
+    pub fn get_data_zones(&mut self) -> Result<String, JsValue> {
+        let roads = self.reachable_network(&ReachabilityOptions::default());
 
         serde_json::to_string(&FeatureCollection {
             bbox: None,
@@ -274,14 +377,19 @@ impl MapModel {
             features: self
                 .data_zones
                 .iter()
-                .map(|x| x.to_gj(&self.graph.mercator, roads.covers_any(&x.roads)))
+                .map(|x| {
+                    x.to_gj(
+                        &self.graph.mercator,
+                        roads.connection_distance_any(&x.roads),
+                    )
+                })
                 .collect(),
         })
         .map_err(err_to_js)
     }
 
     #[wasm_bindgen(js_name = renderReachableNetwork)]
-    pub fn render_reachable_network_wasm(&self) -> Result<String, JsValue> {
+    pub fn render_reachable_network_wasm(&mut self) -> Result<String, JsValue> {
         self.render_reachable_network().map_err(err_to_js)
     }
 
@@ -310,7 +418,7 @@ impl MapModel {
     }
 
     #[wasm_bindgen(js_name = renderPrecalculatedFlows)]
-    pub fn render_precalculated_flows_wasm(&self) -> Result<String, JsValue> {
+    pub fn render_precalculated_flows_wasm(&mut self) -> Result<String, JsValue> {
         self.render_precalculated_flows().map_err(err_to_js)
     }
 
@@ -397,6 +505,20 @@ impl MapModel {
     }
 }
 
+impl MapModel {
+    /// Replace all routes and snapshots with the ones from a savefile
+    pub fn apply_savefile(&mut self, input: &str) -> anyhow::Result<()> {
+        let savefile: Savefile = serde_json::from_str(input)?;
+        // TODO Detect if the savefile is incompatible with the current model (too big RoadIDs) and
+        // bail cleanly
+        self.routes = savefile.routes;
+        self.id_counter = savefile.id_counter;
+        self.snapshots = savefile.snapshots;
+        self.recalculate_after_edits();
+        Ok(())
+    }
+}
+
 #[derive(Deserialize)]
 struct InputRoute {
     feature: Feature,
@@ -420,6 +542,26 @@ struct EvaluateRouteRequest {
     x2: f64,
     y2: f64,
     breakdown: String,
+    #[serde(default)]
+    compare_baseline: bool,
+    #[serde(default)]
+    directness_baseline: DirectnessBaseline,
+}
+
+#[derive(Deserialize)]
+struct IsochroneRequest {
+    x: f64,
+    y: f64,
+    limit_seconds: f64,
+    min_los: Option<LevelOfService>,
+}
+
+#[derive(Deserialize)]
+struct OutcomesRequest {
+    #[serde(default)]
+    od: ODOptions,
+    #[serde(default)]
+    config: OutcomesConfig,
 }
 
 // TODO This is an odd, repetitive format. Redesign later.
@@ -427,6 +569,9 @@ struct EvaluateRouteRequest {
 struct Savefile {
     routes: HashMap<usize, Route>,
     id_counter: usize,
+    // Older savefiles don't have this
+    #[serde(default)]
+    snapshots: BTreeMap<String, Snapshot>,
 }
 
 fn err_to_js<E: std::fmt::Display>(err: E) -> JsValue {
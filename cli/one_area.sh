@@ -2,4 +2,4 @@
 
 AREA=$1
 set -x
-cargo run --release -- --input "../data_prep/osm/out/$AREA.osm.pbf" --boundary "../data_prep/osm/$AREA.geojson" --output "../web/public/areas/$AREA.bin"
+cargo run --release -- build --input "../data_prep/osm/out/$AREA.osm.pbf" --boundary "../data_prep/osm/$AREA.geojson" --output "../web/public/areas/$AREA.bin"
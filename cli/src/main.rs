@@ -1,57 +1,256 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::{BufReader, BufWriter};
 
 use anyhow::{bail, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use elevation::GeoTiffElevation;
 use gdal::{vector::LayerAccess, Dataset};
-use geo::{Distance, Euclidean, Geometry, LineString, MultiPolygon};
+use geo::{Coord, Distance, Euclidean, Geometry, LineString, MultiPolygon};
 use graph::{Graph, Timer};
 use log::info;
 use rstar::{primitives::GeomWithData, RTree, RTreeObject};
-use serde::Deserialize;
-
-use backend::{MapModel, Tier};
+use serde::{Deserialize, Serialize};
+use utils::{
+    osm2graph::{NodeID, OsmID, OsmReader, RelationID, WayID},
+    Tags,
+};
+
+use backend::{
+    existing::Barrier,
+    od::{ODOptions, TripPurpose},
+    rnet::RouteNetwork,
+    MapModel, Tier,
+};
 
 mod match_lines;
 
 #[derive(Parser)]
 struct Args {
-    /// Path to a .osm.pbf or .xml file to convert
-    #[arg(long)]
-    input: String,
-
-    /// Path to GeoJSON file with the boundary to clip the input to
-    #[arg(long)]
-    boundary: String,
+    #[command(subcommand)]
+    command: Command,
+}
 
-    /// Output file to write
-    #[arg(long)]
-    output: String,
+#[derive(Subcommand)]
+enum Command {
+    /// Build a model for one area
+    Build {
+        /// Path to a .osm.pbf or .xml file to convert
+        #[arg(long)]
+        input: String,
+
+        /// Path to GeoJSON file with the boundary to clip the input to
+        #[arg(long)]
+        boundary: String,
+
+        /// Output file to write
+        #[arg(long)]
+        output: String,
+    },
+
+    /// Route all desire lines over a model, then write the route network in the same format as
+    /// NPT's combined network
+    ExportRnet {
+        /// Path to a model created by the build command
+        #[arg(long)]
+        model: String,
+
+        /// Path to a savefile from the web app, with routes to add to the network
+        #[arg(long)]
+        savefile: Option<String>,
+
+        /// JSON overriding the default OD options, like `{"scenario": "GovTarget"}`
+        #[arg(long)]
+        options: Option<String>,
+
+        /// GeoJSON file to write
+        #[arg(long)]
+        geojson: Option<String>,
+
+        /// CSV file to write, with WKT geometry
+        #[arg(long)]
+        csv: Option<String>,
+    },
+
+    /// Write every place where the network or the area reachable from it meets a severance
+    ExportSeveranceCrossings {
+        /// Path to a model created by the build command
+        #[arg(long)]
+        model: String,
+
+        /// Path to a savefile from the web app, with routes to add to the network
+        #[arg(long)]
+        savefile: Option<String>,
+
+        /// GeoJSON file to write, with points
+        #[arg(long)]
+        geojson: Option<String>,
+
+        /// CSV file to write
+        #[arg(long)]
+        csv: Option<String>,
+    },
+
+    /// Route all desire lines over a model, then write how well each one is served
+    ExportDesireLines {
+        /// Path to a model created by the build command
+        #[arg(long)]
+        model: String,
+
+        /// Path to a savefile from the web app, with routes to add to the network
+        #[arg(long)]
+        savefile: Option<String>,
+
+        /// JSON overriding the default OD options, like `{"scenario": "GovTarget"}`. Results are
+        /// always kept per desire line.
+        #[arg(long)]
+        options: Option<String>,
+
+        /// GeoJSON file to write, with lines between zone centroids
+        #[arg(long)]
+        geojson: Option<String>,
+
+        /// CSV file to write
+        #[arg(long)]
+        csv: Option<String>,
+    },
 }
 
 fn main() -> Result<()> {
     simple_logger::init_with_level(log::Level::Info).unwrap();
     let args = Args::parse();
 
-    let mut timer = Timer::new("build model", None);
-    let osm_bytes = std::fs::read(&args.input)?;
-    let boundary_gj = std::fs::read_to_string(&args.boundary)?;
-    let model = create(&osm_bytes, &boundary_gj, &mut timer)?;
+    match args.command {
+        Command::Build {
+            input,
+            boundary,
+            output,
+        } => {
+            let mut timer = Timer::new("build model", None);
+            let osm_bytes = std::fs::read(&input)?;
+            let boundary_gj = std::fs::read_to_string(&boundary)?;
+            let model = create(&osm_bytes, &boundary_gj, &mut timer)?;
+
+            timer.step("writing");
+            let writer = BufWriter::new(File::create(&output)?);
+            bincode::serialize_into(writer, &model)?;
+
+            timer.done();
+        }
+        Command::ExportRnet {
+            model: model_path,
+            savefile,
+            options,
+            geojson,
+            csv,
+        } => {
+            if geojson.is_none() && csv.is_none() {
+                bail!("Pass --geojson, --csv, or both");
+            }
+
+            let opts: ODOptions = match options {
+                Some(json) => serde_json::from_str(&json)?,
+                None => ODOptions::default(),
+            };
+
+            let mut timer = Timer::new("export rnet", None);
+            let mut model = load_model(&model_path, savefile, &mut timer)?;
+
+            let rnet = model.od_route_network(&mut timer, &opts)?;
+
+            timer.step("writing");
+            if let Some(path) = geojson {
+                std::fs::write(&path, rnet.to_geojson()?)?;
+            }
+            if let Some(path) = csv {
+                write_rnet_csv(&path, &rnet)?;
+            }
+
+            timer.done();
+        }
+        Command::ExportDesireLines {
+            model: model_path,
+            savefile,
+            options,
+            geojson,
+            csv,
+        } => {
+            if geojson.is_none() && csv.is_none() {
+                bail!("Pass --geojson, --csv, or both");
+            }
 
-    timer.step("writing");
-    let writer = BufWriter::new(File::create(&args.output)?);
-    bincode::serialize_into(writer, &model)?;
+            let mut opts: ODOptions = match options {
+                Some(json) => serde_json::from_str(&json)?,
+                None => ODOptions::default(),
+            };
+            opts.per_desire_line = true;
 
-    timer.done();
+            let mut timer = Timer::new("export desire lines", None);
+            let mut model = load_model(&model_path, savefile, &mut timer)?;
+            model.recalculate_router(&mut timer);
+
+            timer.step("route OD trips");
+            let out = model.od_counts(&opts)?;
+
+            timer.step("writing");
+            if let Some(path) = geojson {
+                std::fs::write(&path, model.desire_lines_to_geojson(&out.desire_lines)?)?;
+            }
+            if let Some(path) = csv {
+                write_csv(&path, out.desire_lines.iter().map(|x| x.to_record()))?;
+            }
+
+            timer.done();
+        }
+        Command::ExportSeveranceCrossings {
+            model: model_path,
+            savefile,
+            geojson,
+            csv,
+        } => {
+            if geojson.is_none() && csv.is_none() {
+                bail!("Pass --geojson, --csv, or both");
+            }
+
+            let mut timer = Timer::new("export severance crossings", None);
+            let mut model = load_model(&model_path, savefile, &mut timer)?;
+
+            timer.step("find crossings");
+            let crossings = model.severance_crossings();
+
+            timer.step("writing");
+            if let Some(path) = geojson {
+                std::fs::write(&path, model.severance_crossings_to_geojson(&crossings)?)?;
+            }
+            if let Some(path) = csv {
+                write_csv(&path, crossings.iter().map(|x| x.to_record()))?;
+            }
+
+            timer.done();
+        }
+    }
     Ok(())
 }
 
+/// Load a model created by the build command, and optionally routes from a savefile
+fn load_model(path: &str, savefile: Option<String>, timer: &mut Timer) -> Result<MapModel> {
+    timer.step("loading model");
+    let mut model: MapModel = bincode::deserialize_from(BufReader::new(File::open(path)?))?;
+    if let Some(savefile) = savefile {
+        // This recalculates everything that depends on the routes
+        model.apply_savefile(&std::fs::read_to_string(&savefile)?)?;
+    } else {
+        // Infrastructure types, level of service and the baseline router aren't serialized
+        model.recalculate_after_edits();
+    }
+    Ok(model)
+}
+
 fn create(input_bytes: &[u8], boundary_gj: &str, timer: &mut Timer) -> Result<MapModel> {
-    let graph = Graph::new(
+    let mut barrier_reader = BarrierReader::default();
+    let mut graph = Graph::new(
         input_bytes,
-        &mut utils::osm2graph::NullReader,
+        &mut barrier_reader,
         vec![
             (
                 "bicycle".to_string(),
@@ -63,6 +262,10 @@ fn create(input_bytes: &[u8], boundary_gj: &str, timer: &mut Timer) -> Result<Ma
     )?;
     let boundary_wgs84 = read_multipolygon(boundary_gj)?;
 
+    timer.step("finding barriers");
+    let barriers = barrier_reader.find_barriers(&graph);
+    backend::existing::block_impassable_roads(&mut graph, &barriers);
+
     timer.step("loading OD zones");
     let od_zones = backend::od::Zone::parse_zones(
         std::fs::read_to_string("../data_prep/tmp/zones.geojson")?,
@@ -71,7 +274,20 @@ fn create(input_bytes: &[u8], boundary_gj: &str, timer: &mut Timer) -> Result<Ma
     )?;
 
     timer.step("loading desire lines");
-    let desire_lines = read_desire_lines_csv("../data_prep/tmp/od.csv", &od_zones)?;
+    let mut desire_lines = BTreeMap::new();
+    for (purpose, path) in [
+        (TripPurpose::Commute, "../data_prep/tmp/od_commute.csv"),
+        (TripPurpose::School, "../data_prep/tmp/od_school.csv"),
+        (TripPurpose::Utility, "../data_prep/tmp/od_utility.csv"),
+        (TripPurpose::Leisure, "../data_prep/tmp/od_leisure.csv"),
+    ] {
+        // Only commutes are required
+        if purpose != TripPurpose::Commute && !std::path::Path::new(path).exists() {
+            info!("No {path}, skipping {purpose:?} trips");
+            continue;
+        }
+        desire_lines.insert(purpose, read_desire_lines_csv(path, &od_zones)?);
+    }
 
     timer.step("loading schools");
     let schools = backend::places::School::from_gj(
@@ -102,6 +318,13 @@ fn create(input_bytes: &[u8], boundary_gj: &str, timer: &mut Timer) -> Result<Ma
         &graph,
     )?;
 
+    timer.step("loading LADs and regions");
+    let sub_areas = backend::places::SubArea::from_gj(
+        &std::fs::read_to_string("../data_prep/boundaries.geojson")?,
+        &boundary_wgs84,
+        &graph,
+    )?;
+
     let traffic_volumes = read_traffic_volumes("../data_prep/tmp/traffic.gpkg", &graph, timer)?;
 
     let core_network = read_core_network("../data_prep/tmp/core_network.gpkg", &graph, timer)?;
@@ -120,13 +343,101 @@ fn create(input_bytes: &[u8], boundary_gj: &str, timer: &mut Timer) -> Result<Ma
         gp_hospitals,
         town_centres,
         data_zones,
+        sub_areas,
         traffic_volumes,
         core_network,
         precalculated_flows,
         gradients,
+        barriers,
     ))
 }
 
+/// Remembers barrier nodes and the ways that contain them
+#[derive(Default)]
+struct BarrierReader {
+    barriers: HashMap<NodeID, Barrier>,
+    way_nodes: HashMap<WayID, Vec<NodeID>>,
+}
+
+impl OsmReader for BarrierReader {
+    fn node(&mut self, id: NodeID, _: Coord, tags: Tags) {
+        if let Some(barrier) = Barrier::classify(&tags) {
+            self.barriers.insert(id, barrier);
+        }
+    }
+
+    fn way(&mut self, id: WayID, node_ids: &Vec<NodeID>, _: &HashMap<String, String>) {
+        // Nodes are read before ways, so only keep the ways that matter
+        if node_ids.iter().any(|n| self.barriers.contains_key(n)) {
+            self.way_nodes.insert(id, node_ids.clone());
+        }
+    }
+
+    fn relation(&mut self, _: RelationID, _: &Vec<(String, OsmID)>, _: &Tags) {}
+}
+
+impl BarrierReader {
+    /// Per road, find the worst barrier along it. Barriers in the middle of a road and steps
+    /// belong to that road. A barrier at a road's endpoint is shared with every other road there,
+    /// so only one of them gets it -- otherwise one gate at a junction would block every road
+    /// meeting there. Roads whose way ends at the barrier, like a path with a gate where it meets
+    /// a street, are preferred.
+    fn find_barriers(&self, graph: &Graph) -> Vec<Option<Barrier>> {
+        let mut barriers = Vec::new();
+        // For each barrier at an endpoint, every (way continues past it, road index)
+        let mut endpoints: HashMap<NodeID, Vec<(bool, usize)>> = HashMap::new();
+        // Roads from one way are split in order along it, so remember where the last road on each
+        // way ended. Searching the whole way for a road's nodes would find the wrong occurrence
+        // when a way visits a node twice, like a closed way starting and ending at the same node.
+        let mut way_positions: HashMap<WayID, usize> = HashMap::new();
+
+        for (idx, road) in graph.roads.iter().enumerate() {
+            let mut barrier = if road.osm_tags.is("highway", "steps") {
+                Barrier::classify(&road.osm_tags)
+            } else {
+                None
+            };
+
+            if let Some(nodes) = self.way_nodes.get(&road.way) {
+                let position = way_positions.entry(road.way).or_insert(0);
+                let first = (*position..nodes.len()).find(|i| nodes[*i] == road.node1);
+                let last = first
+                    .and_then(|first| (first + 1..nodes.len()).find(|i| nodes[*i] == road.node2));
+                if let (Some(first), Some(last)) = (first, last) {
+                    *position = last;
+                    let interior = nodes[first + 1..last]
+                        .iter()
+                        .filter_map(|n| self.barriers.get(n))
+                        .max()
+                        .cloned();
+                    barrier = barrier.max(interior);
+
+                    for node_idx in [first, last] {
+                        if self.barriers.contains_key(&nodes[node_idx]) {
+                            let way_continues = node_idx != 0 && node_idx != nodes.len() - 1;
+                            endpoints
+                                .entry(nodes[node_idx])
+                                .or_default()
+                                .push((way_continues, idx));
+                        }
+                    }
+                }
+            }
+
+            barriers.push(barrier);
+        }
+
+        for (node, mut roads) in endpoints {
+            // Ties go to the lowest road index, to be deterministic
+            roads.sort();
+            let idx = roads[0].1;
+            barriers[idx] = barriers[idx].max(Some(self.barriers[&node]));
+        }
+
+        barriers
+    }
+}
+
 fn read_multipolygon(gj_string: &str) -> Result<MultiPolygon> {
     let gj: geojson::Feature = gj_string.parse()?;
     if matches!(
@@ -139,15 +450,63 @@ fn read_multipolygon(gj_string: &str) -> Result<MultiPolygon> {
     }
 }
 
+fn write_rnet_csv(path: &str, rnet: &RouteNetwork) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    let mut header = rnet.columns.clone();
+    header.push("geometry".to_string());
+    writer.write_record(&header)?;
+
+    for (linestring, counts) in &rnet.lines {
+        let mut record: Vec<String> = counts.iter().map(|count| count.to_string()).collect();
+        let points: Vec<String> = linestring
+            .0
+            .iter()
+            .map(|pt| format!("{} {}", pt.x, pt.y))
+            .collect();
+        record.push(format!("LINESTRING ({})", points.join(", ")));
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write one CSV row per record, with a header from the field names. The header is written even
+/// if there are no records.
+fn write_csv<T: Serialize + Default>(path: &str, records: impl Iterator<Item = T>) -> Result<()> {
+    // The csv crate only writes the header along with the first record, so get it from a
+    // placeholder
+    let mut placeholder = csv::Writer::from_writer(Vec::new());
+    placeholder.serialize(T::default())?;
+    let bytes = placeholder.into_inner()?;
+    let header = csv::Reader::from_reader(bytes.as_slice())
+        .headers()?
+        .clone();
+
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_path(path)?;
+    writer.write_record(&header)?;
+    for record in records {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
 fn read_desire_lines_csv(
     path: &str,
     zones: &HashMap<String, backend::od::Zone>,
-) -> Result<Vec<(String, String, usize)>> {
+) -> Result<Vec<backend::od::DesireLine>> {
     let mut out = Vec::new();
     for rec in csv::Reader::from_reader(File::open(path)?).deserialize() {
         let row: DesireLineRow = rec?;
         if zones.contains_key(&row.geo_code1) && zones.contains_key(&row.geo_code2) {
-            out.push((row.geo_code1, row.geo_code2, row.all));
+            out.push(backend::od::DesireLine {
+                zone1: row.geo_code1,
+                zone2: row.geo_code2,
+                all: row.all,
+                bicycle: row.bicycle,
+            });
         }
     }
     Ok(out)
@@ -158,6 +517,7 @@ struct DesireLineRow {
     geo_code1: String,
     geo_code2: String,
     all: usize,
+    bicycle: usize,
 }
 
 fn read_traffic_volumes(path: &str, graph: &Graph, timer: &mut Timer) -> Result<Vec<usize>> {
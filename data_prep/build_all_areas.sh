@@ -42,7 +42,7 @@ function build_graph_files {
         for osm in osm/out/*; do
           geojson=$(basename $osm .osm.pbf).geojson
           out=$(basename $osm .osm.pbf).bin
-          task=$(pueue add --print-task-id --escape $bin --input "$osm" --boundary "osm/$geojson" --output "graph-files/$out")
+          task=$(pueue add --print-task-id --escape $bin build --input "$osm" --boundary "osm/$geojson" --output "graph-files/$out")
           # TODO get gzip encoding to work on cloudflare
           #pueue add --after $task --escape gzip "graph-files/$out"
         done
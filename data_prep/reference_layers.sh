@@ -80,7 +80,24 @@ function urban_rural {
 
 function od_and_zones {
   # Manually download https://github.com/nptscot/inputdata/releases/download/v1/desire_lines_scotland.csv from internal GH repo
-  xsv select geo_code1,geo_code2,all $1 > tmp/od.csv
+  # The bicycle column is cycling today, used by the baseline and government target scenarios
+  xsv select geo_code1,geo_code2,all,bicycle $1 > tmp/od_commute.csv
+
+  # Desire lines for travel to school, utility and leisure trips are optional, also from the
+  # internal GH repo. They need the same columns. Utility and leisure trips use the commute uptake
+  # models.
+  for purpose in school utility leisure; do
+    case $purpose in
+      school) input=$2 ;;
+      utility) input=$3 ;;
+      leisure) input=$4 ;;
+    esac
+    if [ -n "$input" ]; then
+      xsv select geo_code1,geo_code2,all,bicycle $input > tmp/od_$purpose.csv
+    else
+      rm -f tmp/od_$purpose.csv
+    fi
+  done
 
   # From https://spatialdata.gov.scot/geonetwork/srv/api/records/389787c0-697d-4824-9ca9-9ce8cb79d6f5
   wget https://maps.gov.scot/ATOM/shapefiles/SG_IntermediateZoneBdry_2011.zip
@@ -122,7 +139,7 @@ schools
 town_centres ~/Downloads/Town_Centres_-_Scotland.json
 gp_and_hospitals ~/Downloads/GP_Practices_-_Scotland.json ~/Downloads/NHS_Hospitals_-_Scotland.json
 urban_rural
-od_and_zones ~/Downloads/desire_lines_scotland.csv
+od_and_zones ~/Downloads/desire_lines_scotland.csv ~/Downloads/desire_lines_school.csv ~/Downloads/desire_lines_utility.csv ~/Downloads/desire_lines_leisure.csv
 traffic ~/Downloads/final_estimates_Scotland.gpkg
 population
 elevation
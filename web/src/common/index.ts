@@ -1,3 +1,4 @@
 export { default as HelpButton } from "./HelpButton.svelte";
 export { default as QualitativeLegend } from "./QualitativeLegend.svelte";
+export { default as SamplingPicker } from "./SamplingPicker.svelte";
 export { layerId } from "./zorder";
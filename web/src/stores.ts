@@ -57,17 +57,105 @@ export let infraTypeMapping: { [name: string]: [string, string] } =
 
 export interface RouteGJ extends FeatureCollection {
   direct_length: number;
-  car_length: number;
+  // Only set when the driving route is the directness baseline and it exists
+  car_length: number | null;
   route_length: number;
+  directness_baseline: DirectnessBaseline;
+  directness_baseline_length: number;
   directions: Step[];
+  // Only set when comparing with the baseline network
+  current?: RouteSummary;
+  baseline?: RouteSummary;
+  differences?: RouteSummary;
 }
 
+export interface RouteSummary {
+  length: number;
+  directness: number;
+  los_shares: { [los: string]: number };
+  infra_type_shares: { [infra_type: string]: number };
+}
+
+// How to pick the endpoints of trips within a zone
+export type Sampling = "Stratified" | "PopulationWeighted" | "RoadNetwork";
+
+export let samplingOptions: [Sampling, string][] = [
+  ["PopulationWeighted", "Where people live"],
+  ["RoadNetwork", "Along roads"],
+  ["Stratified", "Spread across the zone"],
+];
+
+// Used for all OD evaluation and stats
+export let sampling: Writable<Sampling> = writable("PopulationWeighted");
+
+// Any missing option uses the backend's default
+export interface ODOptions {
+  samples_per_desire_line?: number;
+  sampling?: Sampling;
+  seed?: number;
+  scenario?: Scenario;
+  directness_baseline?: DirectnessBaseline;
+  per_desire_line?: boolean;
+}
+
+// Limits on how far destinations can be from the network, in meters. Unlimited by
+// default.
+export interface ReachabilityOptions {
+  max_connection_meters?: number;
+  max_trip_meters?: number;
+}
+
+export interface StatsOptions extends ODOptions {
+  reachability?: ReachabilityOptions;
+  // Defaults to 250 and 400 meters
+  coverage_meters?: number[];
+  // Also break stats down per LAD or region
+  by_area?: boolean;
+}
+
+// What route lengths are compared against, always between snapped positions
+export type DirectnessBaseline = "CarRoute" | "StraightLine" | "ShortestPath";
+
+// Any missing option uses the backend's default
+export interface OutcomesConfig {
+  realised_high_los: number;
+  realised_medium_los: number;
+  realised_low_los: number;
+  realised_should_not_be_used_los: number;
+  trips_per_year: number;
+  car_mode_shift: number;
+  co2_kg_per_car_km: number;
+  cycling_speed_kmh: number;
+  heat_relative_risk: number;
+  heat_reference_minutes_per_week: number;
+  heat_max_risk_reduction: number;
+  mortality_rate: number;
+  value_of_statistical_life: number;
+}
+
+export interface Outcomes {
+  succeeded: number;
+  failed: number;
+  scenario: Scenario;
+  baseline_cyclists: number;
+  current_cyclists: number;
+  additional_cycle_trips_per_year: number;
+  additional_km_cycled_per_year: number;
+  car_km_displaced_per_year: number;
+  co2_saved_tonnes_per_year: number;
+  deaths_prevented_per_year: number;
+  health_value_per_year: number;
+}
+
+export type Scenario = "Baseline" | "GoDutch" | "Ebike" | "GovTarget";
+
 export interface Step {
   name?: string;
   length: number;
   way: string;
   infra_type: string;
   los: string;
+  barrier: "Restrictive" | "Impassable" | null;
 }
 
 export async function autosave() {
@@ -88,35 +176,189 @@ export function assetUrl(path: string): string {
     : path;
 }
 
-export type EvaluateODOut = FeatureCollection & {
+export interface ODSummary {
   succeeded: number;
   failed: number;
-  max_count: number;
   percent_off_network: number;
   percent_on_network: { [name: string]: number };
-};
+}
+
+export type EvaluateODOut = FeatureCollection &
+  ODSummary & {
+    max_count: number;
+    scenario: Scenario;
+    directness_baseline: DirectnessBaseline;
+    // Keyed by trip purpose, only for purposes with desire lines
+    purposes: { [purpose: string]: ODSummary };
+  };
+
+// Also has los_{level} and infra_type_{type} properties with the fraction of the route length
+export type DesireLines = FeatureCollection<
+  LineString,
+  {
+    purpose: string;
+    zone1: string;
+    zone2: string;
+    count: number;
+    succeeded: number;
+    route_length: number;
+    baseline_length: number | null;
+    directness: number | null;
+    uptake: number;
+    [share: string]: number | string | null;
+  }
+>;
+
+export interface ODStats {
+  od_percents_infra_type: { [name: string]: number };
+  od_percents_los: { [name: string]: number };
+}
 
 export type WorstRoutes = [
   { x: number; y: number },
   { x: number; y: number },
 ][];
 
-export interface Stats {
-  od_percents_infra_type: { [name: string]: number };
-  od_percents_los: { [name: string]: number };
-  average_weighted_directness: number;
+// Everything that's also reported per sub-area. Flow quintiles always come from the
+// whole area.
+export interface AreaStats extends ODStats {
+  od_purposes: { [purpose: string]: ODStats };
   percent_reachable_schools: number;
   percent_reachable_gp_hospitals: number;
   percent_reachable_town_centres: number;
   percent_reachable_imd_population: number;
   percent_reachable_population: number;
-  worst_directness_routes: WorstRoutes;
+  reachable_by_distance: {
+    max_connection_meters: number;
+    percent_reachable_schools: number;
+    percent_reachable_gp_hospitals: number;
+    percent_reachable_town_centres: number;
+    percent_reachable_imd_population: number;
+    percent_reachable_population: number;
+  }[];
+  // Lengths in meters
+  network_length: {
+    total: number;
+    by_tier: { [tier: string]: number };
+    by_infra_type: { [infra_type: string]: number };
+    by_los: { [los: string]: number };
+    existing: number;
+    new: number;
+    core_network_total: number;
+    core_network_covered: number;
+    percent_core_network_covered: number;
+    percent_core_network_covered_by_tier: { [tier: string]: number };
+  };
+  // One row per network ("All" or a tier) and distance
+  population_coverage: {
+    network: string;
+    meters: number;
+    percent_population: number;
+    percent_imd_population: number;
+    percent_population_by_density_quintile: number[];
+  }[];
+  equity: {
+    imd_quintiles: EquityRow[];
+    density_quintiles: EquityRow[];
+  };
   covered_flow_quintile_sums: number[];
   total_flow_quintile_sums: number[];
 }
+
+export interface Stats extends AreaStats {
+  scenario: Scenario;
+  directness_baseline: DirectnessBaseline;
+  average_weighted_directness: number;
+  // Keyed by trip purpose, only for purposes with desire lines
+  od_purposes: {
+    [purpose: string]: ODStats & { average_weighted_directness: number };
+  };
+  worst_directness_routes: WorstRoutes;
+  flow_quintile_labels: string[];
+  // Only when requested, for each LAD or region inside the study area
+  by_area?: { name: string; kind: "LAD" | "REGION"; stats: AreaStats }[];
+}
 // For now, the user manually recalculates this
 export let stats: Writable<Stats | null> = writable(null);
 
+// One group of data zones. IMD quintile 1 is the most deprived, and density quintile 1
+// is the densest. Percents are fractions in [0, 1].
+export interface EquityRow {
+  quintile: number;
+  label: string;
+  population: number;
+  percent_population: number;
+  percent_reachable_population: number;
+  // Cycle trips routed over roads in the group, times road length
+  od_km: number;
+  percent_od_km: number;
+  percent_od_km_high_los: number;
+  network_meters: number;
+  percent_network_meters: number;
+  network_meters_per_1000_people: number;
+}
+
+export type SeveranceCrossings = FeatureCollection<
+  Point,
+  {
+    intersection: number;
+    lon: number;
+    lat: number;
+    kind: "network" | "reachable";
+    num_severances: number;
+    name: string | null;
+    speed_mph: number;
+    traffic: number;
+    los: string;
+    class:
+      | "LowSpeedLowTraffic"
+      | "LowSpeedHighTraffic"
+      | "HighSpeedLowTraffic"
+      | "HighSpeedHighTraffic";
+    far_side_population: number;
+    far_side_detour_meters: number | null;
+  }
+>;
+
+export interface GapOptions {
+  weight?: "PrecalculatedFlow" | "Population";
+  max_gap_meters?: number;
+  limit?: number;
+}
+
+// Each feature has everything needed for setRoute
+export type NetworkGaps = FeatureCollection<
+  LineString,
+  RouteProps & {
+    rank: number;
+    length_meters: number;
+    weight: number;
+    score: number;
+  }
+> & {
+  num_components: number;
+  component_population: number[];
+};
+
+// Every number in Stats becomes one of these. Strings or arrays with a different
+// length only have before and after.
+export interface StatDiff {
+  before: any;
+  after: any;
+  change?: number;
+}
+
+// Stats with a StatDiff in place of every number, string or mismatched array
+export type StatsDiff = StatDiff | StatsDiff[] | { [name: string]: StatsDiff };
+
+export type SnapshotDiff = FeatureCollection<
+  LineString,
+  { road: number; before: number; after: number; change: number }
+> & {
+  max_change: number;
+  stats: { [name: string]: StatsDiff };
+};
+
 export type Schools = FeatureCollection<
   Point,
   {
@@ -124,18 +366,30 @@ export type Schools = FeatureCollection<
     name: string;
     pupils: number;
     reachable: boolean;
+    connection_distance: number | null;
     idx: number;
   }
 >;
 
 export type GPHospitals = FeatureCollection<
   Point,
-  { kind: string; name: string; reachable: boolean; idx: number }
+  {
+    kind: string;
+    name: string;
+    reachable: boolean;
+    connection_distance: number | null;
+    idx: number;
+  }
 >;
 
 export type TownCentres = FeatureCollection<
   MultiPolygon,
-  { name?: string; reachable: boolean; idx: number }
+  {
+    name?: string;
+    reachable: boolean;
+    connection_distance: number | null;
+    idx: number;
+  }
 >;
 
 export type DataZones = FeatureCollection<
@@ -147,6 +401,7 @@ export type DataZones = FeatureCollection<
     population: number;
     area_km2: number;
     reachable: boolean;
+    connection_distance: number | null;
     density_quintile: number;
   }
 >;
@@ -157,6 +412,7 @@ export type PrecalculatedFlows = FeatureCollection<
 > & {
   covered_quintile_sums: number[];
   total_quintile_sums: number[];
+  quintile_labels: string[];
 };
 
 export type RouteNode = { snapped: number } | { free: [number, number] };
@@ -9,6 +9,7 @@ import type {
 import type {
   RouteGJ,
   EvaluateODOut,
+  DesireLines,
   Stats,
   Schools,
   GPHospitals,
@@ -18,6 +19,15 @@ import type {
   SetRouteInput,
   RouteNode,
   RouteProps,
+  ODOptions,
+  StatsOptions,
+  DirectnessBaseline,
+  OutcomesConfig,
+  Outcomes,
+  SnapshotDiff,
+  GapOptions,
+  NetworkGaps,
+  SeveranceCrossings,
 } from "./stores";
 
 export class Backend {
@@ -106,6 +116,8 @@ export class Backend {
     start: { lng: number; lat: number };
     end: Position;
     breakdown: "" | "los" | "infra_type" | "gradient";
+    compareBaseline?: boolean;
+    directnessBaseline?: DirectnessBaseline;
   }): RouteGJ {
     this.checkReady();
     return JSON.parse(
@@ -115,18 +127,74 @@ export class Backend {
         x2: req.end[0],
         y2: req.end[1],
         breakdown: req.breakdown,
+        compare_baseline: req.compareBaseline ?? false,
+        directness_baseline: req.directnessBaseline ?? "CarRoute",
       }),
     );
   }
 
-  evaluateOD(): EvaluateODOut {
+  isochrone(req: {
+    start: { lng: number; lat: number };
+    limitSeconds: number;
+    minLos: string | null;
+  }): FeatureCollection {
+    this.checkReady();
+    return JSON.parse(
+      this.inner!.isochrone({
+        x: req.start.lng,
+        y: req.start.lat,
+        limit_seconds: req.limitSeconds,
+        min_los: req.minLos,
+      }),
+    );
+  }
+
+  evaluateOD(opts: ODOptions = {}): EvaluateODOut {
+    this.checkReady();
+    return JSON.parse(this.inner!.evaluateOD(opts));
+  }
+
+  evaluateDesireLines(opts: ODOptions = {}): DesireLines {
+    this.checkReady();
+    return JSON.parse(this.inner!.evaluateDesireLines(opts));
+  }
+
+  recalculateStats(opts: StatsOptions = {}): Stats {
+    this.checkReady();
+    return JSON.parse(this.inner!.recalculateStats(opts));
+  }
+
+  estimateOutcomes(
+    od: ODOptions = {},
+    config: Partial<OutcomesConfig> = {},
+  ): Outcomes {
+    this.checkReady();
+    return JSON.parse(this.inner!.estimateOutcomes({ od, config }));
+  }
+
+  takeSnapshot(name: string, opts: StatsOptions = {}): Stats {
+    this.checkReady();
+    return JSON.parse(this.inner!.takeSnapshot(name, opts));
+  }
+
+  listSnapshots(): { [name: string]: Stats } {
     this.checkReady();
-    return JSON.parse(this.inner!.evaluateOD());
+    return JSON.parse(this.inner!.listSnapshots());
   }
 
-  recalculateStats(): Stats {
+  deleteSnapshot(name: string) {
     this.checkReady();
-    return JSON.parse(this.inner!.recalculateStats());
+    this.inner!.deleteSnapshot(name);
+  }
+
+  restoreSnapshot(name: string) {
+    this.checkReady();
+    this.inner!.restoreSnapshot(name);
+  }
+
+  diffSnapshots(before: string, after: string): SnapshotDiff {
+    this.checkReady();
+    return JSON.parse(this.inner!.diffSnapshots(before, after));
   }
 
   meshDensity(): FeatureCollection {
@@ -162,6 +230,16 @@ export class Backend {
     this.inner!.loadSavefile(contents);
   }
 
+  findNetworkGaps(opts: GapOptions = {}): NetworkGaps {
+    this.checkReady();
+    return JSON.parse(this.inner!.findNetworkGaps(opts));
+  }
+
+  getSeveranceCrossings(): SeveranceCrossings {
+    this.checkReady();
+    return JSON.parse(this.inner!.getSeveranceCrossings());
+  }
+
   getSchools(): Schools {
     this.checkReady();
     return JSON.parse(this.inner!.getSchools());